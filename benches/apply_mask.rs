@@ -0,0 +1,80 @@
+//! Benchmarks of rendering a mask and compositing it over a photo-sized image
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use serezha_watermark_bot::cancellation::CancellationToken;
+use serezha_watermark_bot::mask_generator::{
+    composite_mask, composite_mask_fast, Compositing, MaskConfig, MaskRenderer,
+};
+
+const WIDTH: u32 = 4000;
+const HEIGHT: u32 = 3000;
+
+fn mask_config() -> MaskConfig {
+    text_mask_config(5.0, 30.0, 20.0)
+}
+
+fn text_mask_config(size_percent: f32, stride_x_percent: f32, stride_y_percent: f32) -> MaskConfig {
+    serde_json::from_value(serde_json::json!({
+        "alpha": 32,
+        "content": {
+            "type": "Text",
+            "text": "ЧУПЛЫГИН УХОДИ",
+            "font": "Comic Sans MS",
+            "color": "ffffff",
+            "size": size_percent,
+            "rotation": 45.0,
+            "row_slide": 1.0,
+            "offset_x": -30.0,
+            "stride_x": stride_x_percent,
+            "offset_y": -20.0,
+            "stride_y": stride_y_percent,
+        }
+    }))
+    .unwrap()
+}
+
+fn bench_apply_mask(c: &mut Criterion) {
+    let renderer = MaskRenderer::new();
+    // rendering the mask is the same for both paths, so it's done once
+    let mask = renderer.generate_mask(mask_config(), WIDTH, HEIGHT);
+    let image = image::RgbImage::from_fn(WIDTH, HEIGHT, |x, y| {
+        image::Rgb([x as u8, y as u8, (x ^ y) as u8])
+    });
+    let cancel = CancellationToken::default();
+
+    let mut group = c.benchmark_group("composite_mask");
+    group.sample_size(10);
+    group.bench_function("generic", |b| {
+        b.iter_batched_ref(
+            || image.clone(),
+            |image| composite_mask(&mask, image, Compositing::default(), &cancel).unwrap(),
+            criterion::BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("fast", |b| {
+        b.iter_batched_ref(
+            || image.clone(),
+            |image| composite_mask_fast(&mask, image, &cancel).unwrap(),
+            criterion::BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+fn bench_generate_mask(c: &mut Criterion) {
+    let renderer = MaskRenderer::new();
+
+    let mut group = c.benchmark_group("generate_mask");
+    group.sample_size(10);
+    group.bench_function("text", |b| {
+        b.iter(|| renderer.generate_mask(mask_config(), WIDTH, HEIGHT))
+    });
+    // hundreds of copies of the text
+    group.bench_function("dense_text", |b| {
+        b.iter(|| renderer.generate_mask(text_mask_config(2.0, 8.0, 4.0), WIDTH, HEIGHT))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_apply_mask, bench_generate_mask);
+criterion_main!(benches);
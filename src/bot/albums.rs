@@ -0,0 +1,59 @@
+//! Grouping of the messages sent as an album (media group)
+
+use grammers_client::types::Message;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// How long to wait for more messages of an album after the last one arrived
+///
+/// Telegram delivers the album messages as separate updates, usually in quick succession
+const ALBUM_DEBOUNCE: Duration = Duration::from_millis(1500);
+
+#[derive(Default)]
+struct PendingAlbum {
+    messages: Vec<Message>,
+    /// Incremented on each new message, used to find out which message came last
+    generation: u64,
+}
+
+#[derive(Default)]
+pub struct AlbumCollector {
+    /// Pending albums by `(chat_id, grouped_id)`
+    pending: Mutex<HashMap<(i64, i64), PendingAlbum>>,
+}
+
+impl AlbumCollector {
+    /// Collects all the messages of the album this message belongs to
+    ///
+    /// Returns the messages of the album (ordered by id) to the handler of the message that came last.
+    /// Handlers of the other messages of the album get `None` and should do nothing.
+    /// A message that is not a part of an album is returned as is.
+    pub async fn collect(&self, message: &Message) -> Option<Vec<Message>> {
+        let Some(grouped_id) = message.grouped_id() else {
+            return Some(vec![message.clone()]);
+        };
+        let key = (message.chat().id(), grouped_id);
+
+        let generation = {
+            let mut pending = self.pending.lock().unwrap();
+            let album = pending.entry(key).or_default();
+            album.messages.push(message.clone());
+            album.generation += 1;
+            album.generation
+        };
+
+        tokio::time::sleep(ALBUM_DEBOUNCE).await;
+
+        let mut pending = self.pending.lock().unwrap();
+        if pending.get(&key)?.generation != generation {
+            // a newer message of the album has arrived, its handler will take care of the album
+            return None;
+        }
+
+        let mut album = pending.remove(&key)?;
+        album.messages.sort_by_key(|m| m.id());
+
+        Some(album.messages)
+    }
+}
@@ -0,0 +1,81 @@
+//! Sending a message to all the users of the bot
+
+use crate::bot::retry::retry_send;
+use crate::bot::BotContext;
+use anyhow::Result;
+use grammers_client::types::PackedChat;
+use grammers_client::{Client, InputMessage, InvocationError};
+use tracing::{info, instrument, warn};
+
+/// Errors meaning that the user can't be messaged anymore
+const UNREACHABLE_ERRORS: &[&str] = &[
+    "USER_IS_BLOCKED",
+    "USER_DEACTIVATED",
+    "INPUT_USER_DEACTIVATED",
+    "PEER_ID_INVALID",
+];
+
+#[derive(Debug, Default)]
+pub struct BroadcastReport {
+    pub sent: usize,
+    /// Users that blocked the bot (or deleted their account), they are not messaged again
+    pub blocked: usize,
+    pub failed: usize,
+}
+
+/// Number of the users the broadcast would be sent to
+pub fn count_recipients(context: &BotContext) -> Result<usize> {
+    Ok(context
+        .storage
+        .users(&context.account_name)
+        .recipients()?
+        .len())
+}
+
+/// Sends the message to every user that has talked to the bot, as fast as the send limits allow
+#[instrument(skip_all)]
+pub async fn broadcast(
+    client: &Client,
+    context: &BotContext,
+    message: InputMessage,
+) -> Result<BroadcastReport> {
+    let users = context.storage.users(&context.account_name);
+    let recipients = users.recipients()?;
+    info!("Broadcasting a message to {} users", recipients.len());
+
+    let mut report = BroadcastReport::default();
+    for recipient in recipients {
+        let Ok(chat) = PackedChat::from_bytes(&recipient.packed_chat) else {
+            warn!("Invalid packed chat stored for user {}", recipient.id);
+            report.failed += 1;
+            continue;
+        };
+
+        context.outbox.acquire_private(recipient.id).await;
+        match retry_send("send_message", || {
+            client.send_message(chat, message.clone())
+        })
+        .await
+        {
+            Ok(_) => report.sent += 1,
+            Err(InvocationError::Rpc(e)) if UNREACHABLE_ERRORS.contains(&e.name.as_str()) => {
+                info!(
+                    "User {} is unreachable ({}), marking as blocked",
+                    recipient.id, e.name
+                );
+                users.set_blocked(recipient.id)?;
+                report.blocked += 1;
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to send the broadcast to user {}: {}",
+                    recipient.id, e
+                );
+                report.failed += 1;
+            }
+        }
+    }
+
+    info!("Broadcast finished: {:?}", report);
+    Ok(report)
+}
@@ -0,0 +1,168 @@
+//! Auto-watermarking of the posts in channels
+
+use crate::bot::retry::{retry, retry_send};
+use crate::bot::{download_photos, render_presets, upload_image, BotContext};
+use crate::cancellation::CancellationToken;
+use crate::config;
+use crate::config::{ChannelMode, NamedPreset};
+use crate::storage::Storage;
+use anyhow::{Context as _, Result};
+use grammers_client::types::{Chat, Media, Message};
+use grammers_client::{Client, InputMessage};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tracing::{debug, info, instrument, warn};
+
+/// Channels watched by an account, along with the posts that were already processed
+pub struct ChannelWatcher {
+    channels: HashMap<i64, config::ChannelConfig>,
+    /// Keeps track of the posts that were processed (or are being processed), so that they survive restarts
+    storage: Arc<Storage>,
+    account_name: String,
+    /// Resolved mirror channels, by username
+    mirrors: tokio::sync::Mutex<HashMap<String, Chat>>,
+}
+
+impl ChannelWatcher {
+    pub fn new(
+        storage: Arc<Storage>,
+        account_name: &str,
+        channels: &[config::ChannelConfig],
+    ) -> Self {
+        let channels = channels
+            .iter()
+            // the config assigns each channel to an account
+            .filter(|c| c.account.as_deref() == Some(account_name))
+            .map(|c| (c.id, c.clone()))
+            .collect();
+
+        Self {
+            channels,
+            storage,
+            account_name: account_name.to_string(),
+            mirrors: tokio::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_watched(&self, channel_id: i64) -> bool {
+        self.channels.contains_key(&channel_id)
+    }
+
+    /// Marks the post as processed, returning `false` if it already was
+    fn start_processing(&self, channel_id: i64, message_id: i32) -> Result<bool> {
+        self.storage
+            .channel_posts(&self.account_name)
+            .mark_processed(channel_id, message_id)
+    }
+
+    fn abort_processing(&self, channel_id: i64, message_id: i32) -> Result<()> {
+        self.storage
+            .channel_posts(&self.account_name)
+            .unmark_processed(channel_id, message_id)
+    }
+
+    async fn resolve_mirror(&self, client: &Client, username: &str) -> Result<Chat> {
+        let mut mirrors = self.mirrors.lock().await;
+        if let Some(chat) = mirrors.get(username) {
+            return Ok(chat.clone());
+        }
+
+        let chat = retry("resolve_username", || client.resolve_username(username))
+            .await
+            .context("Resolving the mirror channel")?
+            .with_context(|| format!("Mirror channel @{} not found", username))?;
+        mirrors.insert(username.to_string(), chat.clone());
+
+        Ok(chat)
+    }
+}
+
+/// Handles a new or an edited post in a channel
+///
+/// Each post is processed only once, so our own edits (and further edits of the post) are ignored
+#[instrument(skip_all, fields(channel_id = message.chat().id(), message_id = message.id()), err(Debug))]
+pub async fn handle_channel_post(
+    message: Message,
+    client: Client,
+    context: &BotContext,
+) -> Result<()> {
+    let chat = message.chat();
+    let Chat::Channel(_) = chat else {
+        return Ok(());
+    };
+    let Some(channel) = context.channels.channels.get(&chat.id()) else {
+        return Ok(());
+    };
+    let Some(Media::Photo(photo)) = message.media() else {
+        return Ok(());
+    };
+
+    if !context.channels.start_processing(chat.id(), message.id())? {
+        debug!("Post was already processed");
+        return Ok(());
+    }
+
+    let result = async {
+        let (preset, _) = context
+            .presets
+            .get(&channel.preset)?
+            .with_context(|| format!("Preset `{}` is not available", channel.preset))?;
+        // the jitter is seeded with the channel, so that a reposted image can be traced back to it
+        let preset = NamedPreset {
+            preset: preset.preset.with_request_seed(chat.id() as u64),
+            ..preset
+        };
+
+        // channel posts can't be cancelled
+        let cancel = CancellationToken::default();
+        let photos_data = download_photos(&client, vec![photo], None, &cancel).await?;
+        let mut results =
+            render_presets(&context.renderer, photos_data, vec![preset], None, cancel).await?;
+        let result = results
+            .pop()
+            .and_then(|(_, mut results)| results.pop())
+            .context("No render result")?;
+        let result_file = upload_image(&client, result).await?;
+
+        // keep the original caption along with its formatting
+        let mut caption = InputMessage::text(message.text());
+        if let Some(entities) = message.fmt_entities() {
+            caption = caption.fmt_entities(entities.clone());
+        }
+        let caption = caption.photo(result_file);
+
+        match &channel.mode {
+            ChannelMode::Edit => {
+                info!("Replacing the post photo with the watermarked one");
+                context.outbox.acquire(&chat).await;
+                retry("edit_message", || {
+                    client.edit_message(chat.pack(), message.id(), caption.clone())
+                })
+                .await
+                .context("Editing the post")?;
+            }
+            ChannelMode::Mirror { target } => {
+                info!("Posting the watermarked photo to @{}", target);
+                let target = context.channels.resolve_mirror(&client, target).await?;
+                context.outbox.acquire(&target).await;
+                retry_send("send_message", || {
+                    client.send_message(target.pack(), caption.clone())
+                })
+                .await
+                .context("Posting to the mirror channel")?;
+            }
+        }
+
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+
+    if result.is_err() {
+        // allow retrying on the next edit
+        if let Err(e) = context.channels.abort_processing(chat.id(), message.id()) {
+            warn!("Failed to unmark the post as processed: {:?}", e);
+        }
+    }
+
+    result
+}
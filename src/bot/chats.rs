@@ -0,0 +1,54 @@
+//! Per-chat settings for group chats
+
+use crate::config;
+use crate::storage::{ChatSettings, Storage};
+use anyhow::Result;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Holds the settings of the chats, initialized from the config and changed by chat admins at runtime
+///
+/// The changed settings are saved to the storage and take precedence over the config
+pub struct ChatSettingsStore {
+    storage: Arc<Storage>,
+    account_name: String,
+    defaults: HashMap<i64, ChatSettings>,
+}
+
+impl ChatSettingsStore {
+    pub fn new(storage: Arc<Storage>, account_name: &str, chats: &[config::ChatConfig]) -> Self {
+        let defaults = chats
+            .iter()
+            .map(|chat| {
+                (
+                    chat.id,
+                    ChatSettings {
+                        auto_watermark: chat.auto_watermark,
+                        presets: chat.presets.clone(),
+                    },
+                )
+            })
+            .collect();
+
+        Self {
+            storage,
+            account_name: account_name.to_string(),
+            defaults,
+        }
+    }
+
+    pub fn get(&self, chat_id: i64) -> Result<ChatSettings> {
+        let settings = self.storage.chats(&self.account_name).get(chat_id)?;
+
+        Ok(settings.unwrap_or_else(|| self.defaults.get(&chat_id).cloned().unwrap_or_default()))
+    }
+
+    pub fn set_auto_watermark(&self, chat_id: i64, auto_watermark: bool) -> Result<()> {
+        let mut settings = self.get(chat_id)?;
+        settings.auto_watermark = auto_watermark;
+
+        self.storage
+            .chats(&self.account_name)
+            .set(chat_id, &settings)
+    }
+}
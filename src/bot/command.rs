@@ -0,0 +1,102 @@
+//! Parsing of the bot commands, like `/watermark@some_bot preset name`, and of the mentions of the bot
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Command<'a> {
+    pub name: &'a str,
+    pub args: &'a str,
+}
+
+/// Parses a command from a message text
+///
+/// Returns `None` if the text is not a command or if the command is addressed to some other bot
+pub fn parse_command<'a>(text: &'a str, bot_username: Option<&str>) -> Option<Command<'a>> {
+    let rest = text.trim_start().strip_prefix('/')?;
+    let (head, args) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+
+    let name = match head.split_once('@') {
+        Some((name, target)) => {
+            if !bot_username.is_some_and(|u| u.eq_ignore_ascii_case(target)) {
+                return None;
+            }
+            name
+        }
+        None => head,
+    };
+    if name.is_empty() {
+        return None;
+    }
+
+    Some(Command {
+        name,
+        args: args.trim(),
+    })
+}
+
+/// Whether the text mentions the bot as `@username`
+///
+/// Only whole usernames count, so `@some_bot_fan` doesn't mention `@some_bot`
+pub fn mentions(text: &str, bot_username: &str) -> bool {
+    let is_username_char = |c: char| c.is_ascii_alphanumeric() || c == '_';
+
+    text.match_indices('@').any(|(at, _)| {
+        // like in an email address
+        if text[..at].chars().next_back().is_some_and(is_username_char) {
+            return false;
+        }
+        let rest = &text[at + 1..];
+        let end = rest.find(|c| !is_username_char(c)).unwrap_or(rest.len());
+        rest[..end].eq_ignore_ascii_case(bot_username)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{mentions, parse_command, Command};
+
+    #[test]
+    fn parse_plain() {
+        assert_eq!(
+            parse_command("/watermark", None),
+            Some(Command {
+                name: "watermark",
+                args: ""
+            })
+        );
+        assert_eq!(
+            parse_command("/watermark  ЧУПЛЫГИН УХОДИ #20 ", None),
+            Some(Command {
+                name: "watermark",
+                args: "ЧУПЛЫГИН УХОДИ #20"
+            })
+        );
+        assert_eq!(parse_command("watermark", None), None);
+        assert_eq!(parse_command("/", None), None);
+    }
+
+    #[test]
+    fn parse_addressed() {
+        assert_eq!(
+            parse_command("/watermark@Serezha_Bot arg", Some("serezha_bot")),
+            Some(Command {
+                name: "watermark",
+                args: "arg"
+            })
+        );
+        assert_eq!(
+            parse_command("/watermark@other_bot arg", Some("serezha_bot")),
+            None
+        );
+        assert_eq!(parse_command("/watermark@other_bot", None), None);
+    }
+
+    #[test]
+    fn mention() {
+        assert!(mentions("@serezha_bot", "serezha_bot"));
+        assert!(mentions("look, @Serezha_Bot!", "serezha_bot"));
+        assert!(mentions("@other @serezha_bot\n", "serezha_bot"));
+
+        assert!(!mentions("@serezha_bot_fan", "serezha_bot"));
+        assert!(!mentions("mail@serezha_bot", "serezha_bot"));
+        assert!(!mentions("serezha_bot", "serezha_bot"));
+    }
+}
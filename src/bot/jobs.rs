@@ -0,0 +1,91 @@
+//! Registry of the running jobs, allowing to cancel them from the inline button
+
+use crate::cancellation::CancellationToken;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+const CANCEL_PREFIX: &str = "cancel:";
+
+struct RunningJob {
+    /// The user who started the job
+    owner: i64,
+    cancel: CancellationToken,
+}
+
+#[derive(Default)]
+pub struct Jobs {
+    next_id: AtomicU64,
+    running: Mutex<HashMap<u64, RunningJob>>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CancelResult {
+    Cancelled,
+    NotFound,
+    NotOwner,
+}
+
+impl Jobs {
+    /// Registers a new job, which stays registered until the returned guard is dropped
+    pub fn start(&self, owner: i64) -> JobGuard<'_> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let cancel = CancellationToken::default();
+
+        self.running.lock().unwrap().insert(
+            id,
+            RunningJob {
+                owner,
+                cancel: cancel.clone(),
+            },
+        );
+
+        JobGuard {
+            jobs: self,
+            id,
+            cancel,
+        }
+    }
+
+    /// Cancels the job, if it is still running and the user is allowed to do that
+    pub fn cancel(&self, id: u64, user_id: i64, is_superuser: bool) -> CancelResult {
+        let running = self.running.lock().unwrap();
+        let Some(job) = running.get(&id) else {
+            return CancelResult::NotFound;
+        };
+        if job.owner != user_id && !is_superuser {
+            return CancelResult::NotOwner;
+        }
+
+        job.cancel.cancel();
+        CancelResult::Cancelled
+    }
+}
+
+pub struct JobGuard<'a> {
+    jobs: &'a Jobs,
+    pub id: u64,
+    pub cancel: CancellationToken,
+}
+
+impl JobGuard<'_> {
+    /// Data of the inline button that cancels this job
+    pub fn cancel_button_data(&self) -> String {
+        format!("{}{}", CANCEL_PREFIX, self.id)
+    }
+}
+
+impl Drop for JobGuard<'_> {
+    fn drop(&mut self) {
+        self.jobs.running.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Parses the id of the job from the data of the cancel button
+pub fn parse_cancel_button_data(data: &[u8]) -> Option<u64> {
+    std::str::from_utf8(data)
+        .ok()?
+        .strip_prefix(CANCEL_PREFIX)?
+        .parse()
+        .ok()
+}
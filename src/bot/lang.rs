@@ -7,11 +7,192 @@ pub enum Lang {
     /// Send me an image, please
     NotImage,
 
+    /// Sorry, you are not allowed to use this bot
+    AccessDenied,
+
+    /// Reply to a photo with /watermark to watermark it, optionally specifying a preset name
+    WatermarkUsage,
+
+    /**
+    Unknown preset. Available presets are:
+
+    {0}*/
+    UnknownPreset(String),
+
+    /// Use /auto_watermark on or /auto_watermark off
+    AutoWatermarkUsage,
+
+    /// Only chat admins can change this setting
+    NotChatAdmin,
+
+    /// I will now watermark every photo sent to this chat
+    AutoWatermarkEnabled,
+
+    /// I will now only watermark photos I'm mentioned in
+    AutoWatermarkDisabled,
+
     /// Wowking...
     StatusWorking,
 
+    /// Downloading... {0} of {1} KiB ({2}%)
+    StatusDownloading(usize, usize, usize),
+
+    /// Rendering... {0} of {1}
+    StatusRendering(usize, usize),
+
+    /// Uploading... {0} of {1}
+    StatusUploading(usize, usize),
+
+    /// Cancel
+    ButtonCancel,
+
+    /// Cancelling...
+    CancelRequested,
+
+    /// This job has already finished
+    CancelJobNotFound,
+
+    /// Only the one who started the job can cancel it
+    CancelNotOwner,
+
     /// Here's your processed images
     ResultSuccess,
+
+    /// Cancelled
+    ResultCancelled,
+
+    /// Only superusers can do that
+    SuperuserOnly,
+
+    /**
+    Last 24 hours: {0}
+
+    Last 7 days: {1}
+
+    Top users in the last 7 days:
+    {2}
+
+    Top presets in the last 7 days:
+    {3}*/
+    StatsReport(String, String, String, String),
+
+    /// {0} jobs with {1} photos, {2}% failed, {3} cancelled. Average time {4}s (rendering {5}s, uploading {6}s)
+    StatsPeriod(u64, u64, u64, u64, String, String, String),
+
+    /// no jobs
+    StatsNoJobs,
+
+    /**
+    Use /broadcast with the text to send, or reply with it to the message to send.
+    Use /broadcast --dry-run to see how many users will get the message*/
+    BroadcastUsage,
+
+    /// The broadcast would be sent to {0} users
+    BroadcastDryRun(usize),
+
+    /// Sending the broadcast to {0} users...
+    BroadcastStarted(usize),
+
+    /// Broadcast finished: {0} sent, {1} blocked the bot, {2} failed
+    BroadcastFinished(usize, usize, usize),
+
+    /**
+    Manage the presets with:
+    /preset_list
+    /preset_preview name
+    /preset_new name, followed by the preset YAML on the next lines
+    /preset_edit name, followed by the preset YAML on the next lines
+    /preset_delete name*/
+    PresetUsage,
+
+    /**
+    Presets:
+
+    {0}*/
+    PresetList(String),
+
+    /// {0} (from the config)
+    PresetListConfigItem(String),
+
+    /// {0} (added in the chat)
+    PresetListChatItem(String),
+
+    /// Preset `{0}` not found
+    PresetNotFound(String),
+
+    /// Preset `{0}` already exists
+    PresetExists(String),
+
+    /// Preset `{0}` is defined in the config and can only be changed there
+    PresetFromConfig(String),
+
+    /**
+    Invalid preset:
+
+    {0}*/
+    PresetInvalid(String),
+
+    /// Preset `{0}`
+    PresetPreview(String),
+
+    /// Preset `{0}` saved, here's how it looks
+    PresetSaved(String),
+
+    /// Preset `{0}` deleted
+    PresetDeleted(String),
+
+    /**
+    You can have up to {0} personal presets, with your own text in the style of one of the presets:
+    /my_presets to list them
+    /my_preset_new name, followed by the name of the preset to take the style from and the text on the next lines
+    /my_preset_off name to stop applying it to your photos, /my_preset_on name to apply it again
+    /my_preset_delete name*/
+    MyPresetUsage(usize),
+
+    /**
+    Your presets:
+
+    {0}*/
+    MyPresetList(String),
+
+    /// {0}: "{2}" in the style of {1}
+    MyPresetListItem(String, String, String),
+
+    /// {0}: "{2}" in the style of {1}, off
+    MyPresetListItemOff(String, String, String),
+
+    /// You don't have personal presets yet
+    MyPresetListEmpty,
+
+    /// You already have {0} presets, delete some of them first
+    MyPresetLimit(usize),
+
+    /// The text must be a single line of at most {0} characters
+    MyPresetInvalidText(usize),
+
+    /**
+    Unknown style. Available styles are:
+
+    {0}*/
+    MyPresetUnknownTemplate(String),
+
+    /// Preset `{0}` already exists
+    MyPresetExists(String),
+
+    /// You don't have a preset `{0}`
+    MyPresetNotFound(String),
+
+    /// Your preset `{0}` is saved, here's how it looks
+    MyPresetSaved(String),
+
+    /// Your preset `{0}` is deleted
+    MyPresetDeleted(String),
+
+    /// Your preset `{0}` will be applied to your photos
+    MyPresetOn(String),
+
+    /// Your preset `{0}` won't be applied to your photos anymore, but you can still pick it with /watermark
+    MyPresetOff(String),
     /**
     Some error occurred:
 
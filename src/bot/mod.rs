@@ -1,28 +1,133 @@
+mod albums;
+mod broadcast;
+mod channels;
+mod chats;
+mod command;
+mod jobs;
 mod lang;
+mod outbox;
+mod presets;
+mod retry;
+mod stats;
+mod status;
 
+use crate::bot::albums::AlbumCollector;
+use crate::bot::broadcast::{broadcast, count_recipients};
+use crate::bot::channels::{handle_channel_post, ChannelWatcher};
+use crate::bot::chats::ChatSettingsStore;
+use crate::bot::command::{mentions, parse_command, Command};
+use crate::bot::jobs::{parse_cancel_button_data, CancelResult, Jobs};
 use crate::bot::lang::Lang;
+use crate::bot::outbox::Outbox;
+use crate::bot::presets::{
+    render_preview, resolve_user_preset, PresetSource, PresetStore, MAX_USER_PRESETS,
+    MAX_USER_PRESET_TEXT_LEN,
+};
+use crate::bot::retry::{retry, retry_send};
+use crate::bot::stats::stats_report;
+use crate::bot::status::StatusMessage;
+use crate::cancellation::{CancellationToken, Cancelled};
 use crate::config;
 use crate::config::NamedPreset;
+use crate::mask_generator::MaskRenderer;
+use crate::storage::{JobOutcome, JobRecord, Storage, UserPreset};
 use anyhow::{Context as _, Result};
-use grammers_client::types::{Chat, Downloadable, Media, Message};
-use grammers_client::{Client, InputMessage, Update};
+use grammers_client::types::{CallbackQuery, Chat, Downloadable, Media, Message, Photo, Uploaded};
+use grammers_client::{Client, InputMedia, InputMessage, Update};
 use std::io::Cursor;
-use tracing::{debug, error, info, instrument};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, instrument, warn};
+
+/// Everything a single account needs to handle its updates
+pub struct BotContext {
+    pub account_name: String,
+    pub presets: PresetStore,
+    pub access: config::Access,
+    pub chats: ChatSettingsStore,
+    pub channels: ChannelWatcher,
+    pub albums: AlbumCollector,
+    pub jobs: Jobs,
+    pub outbox: Outbox,
+    /// Shared between all the accounts
+    pub renderer: Arc<MaskRenderer>,
+    /// Shared between all the accounts, each of them only sees its own data
+    pub storage: Arc<Storage>,
+}
+
+impl BotContext {
+    pub fn new(
+        config: &config::Config,
+        account: &config::Account,
+        renderer: Arc<MaskRenderer>,
+        storage: Arc<Storage>,
+    ) -> Self {
+        Self {
+            account_name: account.name.clone(),
+            presets: PresetStore::new(
+                account.presets(&config.masks),
+                storage.clone(),
+                &account.name,
+            ),
+            access: account.access.clone().unwrap_or(config.access.clone()),
+            chats: ChatSettingsStore::new(storage.clone(), &account.name, &config.chats),
+            channels: ChannelWatcher::new(storage.clone(), &account.name, &config.channels),
+            albums: AlbumCollector::default(),
+            jobs: Jobs::default(),
+            outbox: Outbox::new(),
+            renderer,
+            storage,
+        }
+    }
+}
+
+/// Information about the account the bot is running as
+struct Me {
+    /// Lowercase username, used to detect mentions and commands addressed to the bot
+    username: Option<String>,
+}
+
+pub async fn run_bot(client: &Client, context: Arc<BotContext>) -> Result<()> {
+    let me = client.get_me().await.context("Getting own user")?;
+    let me = Arc::new(Me {
+        username: me.username().map(|u| u.to_lowercase()),
+    });
 
-pub async fn run_bot(client: &Client, mask_config: config::Mask) -> Result<()> {
     while let Some(update) = client.next_update().await.context("Getting next update")? {
-        let Update::NewMessage(message) = update else {
-            continue;
+        let message = match update {
+            Update::NewMessage(message) | Update::MessageEdited(message)
+                if context.channels.is_watched(message.chat().id()) =>
+            {
+                // channel posts made by this account itself are processed too
+                let client = client.clone();
+                let context = context.clone();
+                tokio::spawn(async move {
+                    // error are logged by tracing instrument macro
+                    let _ = handle_channel_post(message, client, &context).await;
+                });
+                continue;
+            }
+            Update::NewMessage(message) => message,
+            Update::CallbackQuery(query) => {
+                let context = context.clone();
+                tokio::spawn(async move {
+                    // error are logged by tracing instrument macro
+                    let _ = handle_callback_query(query, context).await;
+                });
+                continue;
+            }
+            _ => continue,
         };
         if message.outgoing() {
             continue;
         }
 
         let client = client.clone();
-        let mask_config = mask_config.clone();
+        let context = context.clone();
+        let me = me.clone();
         tokio::spawn(async move {
             // error are logged by tracing instrument macro
-            let _ = handle_message(message, client, mask_config).await;
+            let _ = handle_message(message, client, context, me).await;
         });
     }
 
@@ -30,21 +135,56 @@ pub async fn run_bot(client: &Client, mask_config: config::Mask) -> Result<()> {
     Ok(())
 }
 
+#[instrument(skip_all, fields(chat_id = query.chat().id(), user_id = query.sender().id()), err(Debug))]
+async fn handle_callback_query(query: CallbackQuery, context: Arc<BotContext>) -> Result<()> {
+    let Some(job_id) = parse_cancel_button_data(query.data()) else {
+        debug!("Ignoring unknown callback query data {:?}", query.data());
+        return Ok(());
+    };
+
+    let user_id = query.sender().id();
+    let answer = match context
+        .jobs
+        .cancel(job_id, user_id, context.access.is_superuser(user_id))
+    {
+        CancelResult::Cancelled => {
+            info!("Cancelling job {}", job_id);
+            Lang::CancelRequested
+        }
+        CancelResult::NotFound => Lang::CancelJobNotFound,
+        CancelResult::NotOwner => Lang::CancelNotOwner,
+    };
+
+    query
+        .answer()
+        .text(answer.to_string())
+        .send()
+        .await
+        .context("Answering the callback query")?;
+
+    Ok(())
+}
+
 pub enum MessageResult {
     Reply(InputMessage),
     Ignore,
 }
 
 #[instrument(skip_all, fields(chat_id = message.chat().id(), username = message.chat().username()), err(Debug))]
-async fn handle_message(message: Message, client: Client, mask_config: config::Mask) -> Result<()> {
-    let result = handle_message_impl(&message, client, mask_config).await;
+async fn handle_message(
+    message: Message,
+    client: Client,
+    context: Arc<BotContext>,
+    me: Arc<Me>,
+) -> Result<()> {
+    let result = handle_message_impl(&message, client, context.clone(), me).await;
 
     // reply to the user if there's an error or the handler requested a reply.
     // any error here will only be reported to the tracing, not to the user (because sending a message after a failed message will probably fail too..)
     match result {
         Ok(MessageResult::Reply(reply)) => {
-            message
-                .reply(reply)
+            context.outbox.acquire(&message.chat()).await;
+            retry_send("reply", || message.reply(reply.clone()))
                 .await
                 .context("Replying to the message")?;
         }
@@ -59,8 +199,9 @@ async fn handle_message(message: Message, client: Client, mask_config: config::M
             // TODO: make the error a code block
             // the markdown parser seems a bit buggy, so can't really use it here.
             // TODO: and now that a Lang is here, it's even less clear as to how
-            message
-                .reply(Lang::ResultGenericError(report))
+            let report = InputMessage::from(Lang::ResultGenericError(report));
+            context.outbox.acquire(&message.chat()).await;
+            retry_send("reply", || message.reply(report.clone()))
                 .await
                 .context("Sending the error message to the user")?;
         }
@@ -73,74 +214,802 @@ async fn handle_message(message: Message, client: Client, mask_config: config::M
 async fn handle_message_impl(
     message: &Message,
     client: Client,
-    mask_config: config::Mask,
+    context: Arc<BotContext>,
+    me: Arc<Me>,
 ) -> Result<MessageResult> {
     let chat = message.chat();
     debug!("Got message from {:?}", chat.id());
-    if !matches!(chat, Chat::User(_)) {
-        info!("Ignoring message not from private chat ({:?})", chat);
+
+    let sender_id = message.sender().map(|s| s.id()).unwrap_or(chat.id());
+
+    match chat {
+        Chat::User(_) => {
+            if !context.access.is_allowed(sender_id) {
+                info!("Denying access to user {}", sender_id);
+                return Ok(MessageResult::Reply(Lang::AccessDenied.into()));
+            }
+
+            // remember the user, so that we can message them later
+            context.storage.users(&context.account_name).touch(
+                sender_id,
+                chat.username(),
+                &chat.pack().to_bytes(),
+            )?;
+
+            if let Some(command) = parse_command(message.text(), me.username.as_deref()) {
+                if let Some(result) =
+                    handle_private_command(message, command, sender_id, &client, &context).await?
+                {
+                    return Ok(result);
+                }
+            }
+
+            if !matches!(message.media(), Some(Media::Photo(_))) {
+                return Ok(MessageResult::Reply(Lang::NotImage.into()));
+            }
+
+            let Some(album) = context.albums.collect(message).await else {
+                return Ok(MessageResult::Ignore);
+            };
+
+            watermark_photos(
+                &album[0],
+                album_photos(&album),
+                context.presets.for_user(sender_id)?,
+                sender_id,
+                &client,
+                &context,
+            )
+            .await
+        }
+        Chat::Group(_) => {
+            // stay quiet in groups, only answering to things addressed to us
+            if !context.access.is_allowed(sender_id) {
+                debug!("Ignoring message from a not allowed user {}", sender_id);
+                return Ok(MessageResult::Ignore);
+            }
+
+            handle_group_message(message, sender_id, &client, &context, &me).await
+        }
+        Chat::Channel(_) => {
+            info!("Ignoring message from a channel ({:?})", chat);
+            Ok(MessageResult::Ignore)
+        }
+    }
+}
+
+/// Handles the commands sent in private, returning `None` if the command is not known
+async fn handle_private_command(
+    message: &Message,
+    command: Command<'_>,
+    sender_id: i64,
+    client: &Client,
+    context: &BotContext,
+) -> Result<Option<MessageResult>> {
+    let superuser_only =
+        matches!(command.name, "stats" | "broadcast") || command.name.starts_with("preset");
+    if superuser_only && !context.access.is_superuser(sender_id) {
+        return Ok(Some(MessageResult::Reply(Lang::SuperuserOnly.into())));
+    }
+
+    match command.name {
+        "stats" => {
+            let report = stats_report(&context.storage, &context.account_name)?;
+            Ok(Some(MessageResult::Reply(report.into())))
+        }
+        "broadcast" => {
+            if command.args == "--dry-run" {
+                let count = count_recipients(context)?;
+                return Ok(Some(MessageResult::Reply(
+                    Lang::BroadcastDryRun(count).into(),
+                )));
+            }
+
+            let reply = message
+                .get_reply()
+                .await
+                .context("Getting the replied message")?;
+            let broadcast_message = match (reply, command.args) {
+                // keep the formatting and the media of the replied message
+                (Some(reply), _) => {
+                    let mut broadcast_message = InputMessage::text(reply.text());
+                    if let Some(entities) = reply.fmt_entities() {
+                        broadcast_message = broadcast_message.fmt_entities(entities.clone());
+                    }
+                    if let Some(media) = reply.media() {
+                        broadcast_message = broadcast_message.copy_media(&media);
+                    }
+                    broadcast_message
+                }
+                (None, "") => return Ok(Some(MessageResult::Reply(Lang::BroadcastUsage.into()))),
+                (None, text) => InputMessage::text(text),
+            };
+
+            let count = count_recipients(context)?;
+            context.outbox.acquire(&message.chat()).await;
+            retry_send("reply", || {
+                message.reply(InputMessage::from(Lang::BroadcastStarted(count)))
+            })
+            .await
+            .context("Replying to the message")?;
+
+            let report = broadcast(client, context, broadcast_message).await?;
+            Ok(Some(MessageResult::Reply(
+                Lang::BroadcastFinished(report.sent, report.blocked, report.failed).into(),
+            )))
+        }
+        "presets" | "preset_help" => Ok(Some(MessageResult::Reply(Lang::PresetUsage.into()))),
+        "preset_list" => {
+            let list = context
+                .presets
+                .list()?
+                .into_iter()
+                .map(|(preset, source)| match source {
+                    PresetSource::Config => Lang::PresetListConfigItem(preset.name),
+                    PresetSource::Chat => Lang::PresetListChatItem(preset.name),
+                })
+                .map(|item| item.to_string())
+                .collect::<Vec<_>>()
+                .join("\n");
+            Ok(Some(MessageResult::Reply(Lang::PresetList(list).into())))
+        }
+        "preset_preview" => {
+            let name = command.args;
+            let Some((preset, _)) = context.presets.get(name)? else {
+                return Ok(Some(MessageResult::Reply(
+                    Lang::PresetNotFound(name.to_string()).into(),
+                )));
+            };
+            let caption = Lang::PresetPreview(preset.name.clone());
+            send_preview(message, preset, caption, client, context).await?;
+            Ok(Some(MessageResult::Ignore))
+        }
+        "preset_new" | "preset_edit" => {
+            // the name is on the first line, the definition is on the rest
+            let (name, definition) = command.args.split_once('\n').unwrap_or((command.args, ""));
+            let (name, definition) = (name.trim(), definition.trim());
+            if name.is_empty() || definition.is_empty() {
+                return Ok(Some(MessageResult::Reply(Lang::PresetUsage.into())));
+            }
+            if context.presets.is_from_config(name) {
+                return Ok(Some(MessageResult::Reply(
+                    Lang::PresetFromConfig(name.to_string()).into(),
+                )));
+            }
+
+            let preset = match config::parse_preset(definition) {
+                Ok(preset) => preset,
+                Err(e) => {
+                    return Ok(Some(MessageResult::Reply(
+                        Lang::PresetInvalid(format!("{:#}", e)).into(),
+                    )))
+                }
+            };
+
+            if command.name == "preset_new" {
+                if !context.presets.insert(name, definition, sender_id)? {
+                    return Ok(Some(MessageResult::Reply(
+                        Lang::PresetExists(name.to_string()).into(),
+                    )));
+                }
+            } else if !context.presets.update(name, definition)? {
+                return Ok(Some(MessageResult::Reply(
+                    Lang::PresetNotFound(name.to_string()).into(),
+                )));
+            }
+            info!("Preset `{}` saved by {}", name, sender_id);
+
+            let preset = NamedPreset {
+                name: name.to_string(),
+                preset,
+            };
+            let caption = Lang::PresetSaved(name.to_string());
+            send_preview(message, preset, caption, client, context).await?;
+            Ok(Some(MessageResult::Ignore))
+        }
+        "preset_delete" => {
+            let name = command.args;
+            if context.presets.is_from_config(name) {
+                return Ok(Some(MessageResult::Reply(
+                    Lang::PresetFromConfig(name.to_string()).into(),
+                )));
+            }
+            if !context.presets.delete(name)? {
+                return Ok(Some(MessageResult::Reply(
+                    Lang::PresetNotFound(name.to_string()).into(),
+                )));
+            }
+            info!("Preset `{}` deleted by {}", name, sender_id);
+
+            Ok(Some(MessageResult::Reply(
+                Lang::PresetDeleted(name.to_string()).into(),
+            )))
+        }
+        "my_preset" | "my_preset_help" => Ok(Some(MessageResult::Reply(
+            Lang::MyPresetUsage(MAX_USER_PRESETS).into(),
+        ))),
+        "my_presets" => {
+            let presets = context.presets.user_presets(sender_id)?;
+            if presets.is_empty() {
+                return Ok(Some(MessageResult::Reply(Lang::MyPresetListEmpty.into())));
+            }
+            let list = presets
+                .into_iter()
+                .map(|p| match p.active {
+                    true => Lang::MyPresetListItem(p.name, p.template, p.text).to_string(),
+                    false => Lang::MyPresetListItemOff(p.name, p.template, p.text).to_string(),
+                })
+                .collect::<Vec<_>>()
+                .join("\n");
+            Ok(Some(MessageResult::Reply(Lang::MyPresetList(list).into())))
+        }
+        "my_preset_new" => {
+            let mut lines = command.args.splitn(3, '\n').map(str::trim);
+            let (Some(name), Some(template), Some(text)) =
+                (lines.next(), lines.next(), lines.next())
+            else {
+                return Ok(Some(MessageResult::Reply(
+                    Lang::MyPresetUsage(MAX_USER_PRESETS).into(),
+                )));
+            };
+            if name.is_empty() {
+                return Ok(Some(MessageResult::Reply(
+                    Lang::MyPresetUsage(MAX_USER_PRESETS).into(),
+                )));
+            }
+            if text.is_empty()
+                || text.contains('\n')
+                || text.chars().count() > MAX_USER_PRESET_TEXT_LEN
+            {
+                return Ok(Some(MessageResult::Reply(
+                    Lang::MyPresetInvalidText(MAX_USER_PRESET_TEXT_LEN).into(),
+                )));
+            }
+            if context.presets.user_presets(sender_id)?.len() >= MAX_USER_PRESETS {
+                return Ok(Some(MessageResult::Reply(
+                    Lang::MyPresetLimit(MAX_USER_PRESETS).into(),
+                )));
+            }
+            // personal presets are listed along with the global ones, so the names must not clash
+            if context.presets.get(name)?.is_some() {
+                return Ok(Some(MessageResult::Reply(
+                    Lang::MyPresetExists(name.to_string()).into(),
+                )));
+            }
+
+            let templates = context.presets.templates()?;
+            let preset = UserPreset {
+                name: name.to_string(),
+                template: template.to_string(),
+                text: text.to_string(),
+                active: true,
+            };
+            let Some(resolved) = resolve_user_preset(&templates, &preset) else {
+                return Ok(Some(MessageResult::Reply(
+                    Lang::MyPresetUnknownTemplate(preset_list(&templates)).into(),
+                )));
+            };
+
+            if !context.presets.insert_user_preset(sender_id, &preset)? {
+                return Ok(Some(MessageResult::Reply(
+                    Lang::MyPresetExists(name.to_string()).into(),
+                )));
+            }
+            info!("User {} added a personal preset `{}`", sender_id, name);
+
+            let caption = Lang::MyPresetSaved(name.to_string());
+            send_preview(message, resolved, caption, client, context).await?;
+            Ok(Some(MessageResult::Ignore))
+        }
+        "my_preset_on" | "my_preset_off" => {
+            let name = command.args;
+            let active = command.name == "my_preset_on";
+            if !context
+                .presets
+                .set_user_preset_active(sender_id, name, active)?
+            {
+                return Ok(Some(MessageResult::Reply(
+                    Lang::MyPresetNotFound(name.to_string()).into(),
+                )));
+            }
+
+            let reply = match active {
+                true => Lang::MyPresetOn(name.to_string()),
+                false => Lang::MyPresetOff(name.to_string()),
+            };
+            Ok(Some(MessageResult::Reply(reply.into())))
+        }
+        "my_preset_delete" => {
+            let name = command.args;
+            if !context.presets.delete_user_preset(sender_id, name)? {
+                return Ok(Some(MessageResult::Reply(
+                    Lang::MyPresetNotFound(name.to_string()).into(),
+                )));
+            }
+
+            Ok(Some(MessageResult::Reply(
+                Lang::MyPresetDeleted(name.to_string()).into(),
+            )))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Replies with the preset applied to a sample image
+async fn send_preview(
+    message: &Message,
+    preset: NamedPreset,
+    caption: Lang,
+    client: &Client,
+    context: &BotContext,
+) -> Result<()> {
+    let caption = InputMessage::from(caption);
+    // seeded like the watermarks made for the one asking, so that the preview looks like them
+    let seed = message.sender().map_or(message.chat().id(), |s| s.id());
+    let preset = NamedPreset {
+        preset: preset.preset.with_request_seed(seed as u64),
+        ..preset
+    };
+    let preview = render_preview(&context.renderer, preset).await?;
+    let preview = upload_image(client, preview).await?;
+
+    context.outbox.acquire(&message.chat()).await;
+    retry_send("reply", || {
+        message.reply(caption.clone().photo(preview.clone()))
+    })
+    .await
+    .context("Sending the preset preview")?;
+
+    Ok(())
+}
+
+async fn handle_group_message(
+    message: &Message,
+    sender_id: i64,
+    client: &Client,
+    context: &BotContext,
+    me: &Me,
+) -> Result<MessageResult> {
+    let chat = message.chat();
+    let settings = context.chats.get(chat.id())?;
+
+    let chat_presets = context
+        .presets
+        .all()?
+        .into_iter()
+        .filter(|p| {
+            settings
+                .presets
+                .as_ref()
+                .is_none_or(|names| names.contains(&p.name))
+        })
+        .collect::<Vec<_>>();
+
+    if let Some(command) = parse_command(message.text(), me.username.as_deref()) {
+        match command.name {
+            "watermark" => {
+                let Some(reply) = message
+                    .get_reply()
+                    .await
+                    .context("Getting the replied message")?
+                else {
+                    return Ok(MessageResult::Reply(Lang::WatermarkUsage.into()));
+                };
+                let Some(Media::Photo(photo)) = reply.media() else {
+                    return Ok(MessageResult::Reply(Lang::WatermarkUsage.into()));
+                };
+
+                let presets = if command.args.is_empty() {
+                    chat_presets
+                } else {
+                    // the sender can pick one of their personal presets too
+                    let mut candidates = chat_presets;
+                    let templates = context.presets.all()?;
+                    candidates.extend(context.presets.personal_presets(sender_id, &templates)?);
+
+                    let presets = candidates
+                        .iter()
+                        .filter(|p| p.name.to_lowercase() == command.args.to_lowercase())
+                        .cloned()
+                        .collect::<Vec<_>>();
+                    if presets.is_empty() {
+                        return Ok(MessageResult::Reply(
+                            Lang::UnknownPreset(preset_list(&candidates)).into(),
+                        ));
+                    }
+                    presets
+                };
+
+                return watermark_photos(message, vec![photo], presets, sender_id, client, context)
+                    .await;
+            }
+            "auto_watermark" => {
+                let enable = match command.args {
+                    "on" => true,
+                    "off" => false,
+                    _ => return Ok(MessageResult::Reply(Lang::AutoWatermarkUsage.into())),
+                };
+
+                if !context.access.is_superuser(sender_id) {
+                    let sender = message.sender().context("Message has no sender")?;
+                    let permissions = client
+                        .get_permissions(chat.pack(), sender.pack())
+                        .await
+                        .context("Getting the sender permissions")?;
+                    if !permissions.is_admin() && !permissions.is_creator() {
+                        return Ok(MessageResult::Reply(Lang::NotChatAdmin.into()));
+                    }
+                }
+
+                info!("Setting auto watermark in chat {} to {}", chat.id(), enable);
+                context.chats.set_auto_watermark(chat.id(), enable)?;
+
+                return Ok(MessageResult::Reply(if enable {
+                    Lang::AutoWatermarkEnabled.into()
+                } else {
+                    Lang::AutoWatermarkDisabled.into()
+                }));
+            }
+            // might be a command for some other bot
+            _ => return Ok(MessageResult::Ignore),
+        }
+    }
+
+    if !matches!(message.media(), Some(Media::Photo(_))) {
+        return Ok(MessageResult::Ignore);
     }
 
-    let Some(Media::Photo(photo)) = message.media() else {
-        return Ok(MessageResult::Reply(Lang::NotImage.into()));
+    let Some(album) = context.albums.collect(message).await else {
+        return Ok(MessageResult::Ignore);
     };
 
-    let status_message = message
-        .reply(Lang::StatusWorking)
+    // usually only one message of an album has a caption
+    let mentioned = me
+        .username
+        .as_ref()
+        .is_some_and(|username| album.iter().any(|m| mentions(m.text(), username)));
+
+    if mentioned || settings.auto_watermark {
+        watermark_photos(
+            &album[0],
+            album_photos(&album),
+            chat_presets,
+            sender_id,
+            client,
+            context,
+        )
         .await
-        .context("Sending status message")?;
+    } else {
+        Ok(MessageResult::Ignore)
+    }
+}
+
+fn album_photos(album: &[Message]) -> Vec<Photo> {
+    album
+        .iter()
+        .filter_map(|m| match m.media() {
+            Some(Media::Photo(photo)) => Some(photo),
+            _ => None,
+        })
+        .collect()
+}
+
+fn preset_list(presets: &[NamedPreset]) -> String {
+    presets
+        .iter()
+        .map(|p| p.name.as_str())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Telegram doesn't allow more than 10 items in an album
+const MAX_ALBUM_SIZE: usize = 10;
+
+/// Applies the presets to the photos and sends the results as replies to the message
+///
+/// Results of each preset are sent as a separate album.
+/// The job can be cancelled by the `owner` with a button on the status message,
+/// in which case the results sent so far are deleted.
+async fn watermark_photos(
+    message: &Message,
+    photos: Vec<Photo>,
+    presets: Vec<NamedPreset>,
+    owner: i64,
+    client: &Client,
+    context: &BotContext,
+) -> Result<MessageResult> {
+    let preset_names = presets.iter().map(|p| p.name.clone()).collect();
+    let photo_count = photos.len();
+    // the jitter is seeded with the user the results are made for, so that a leaked image can be traced back
+    let presets = presets
+        .into_iter()
+        .map(|p| NamedPreset {
+            preset: p.preset.with_request_seed(owner as u64),
+            ..p
+        })
+        .collect();
+
+    let started = Instant::now();
+    let job = context.jobs.start(owner);
+    let mut status =
+        StatusMessage::send(message, &context.outbox, job.cancel_button_data()).await?;
+    let mut progress = JobProgress::default();
+
+    let result = send_watermarked_photos(
+        message,
+        photos,
+        presets,
+        client,
+        context,
+        &job.cancel,
+        &mut status,
+        &mut progress,
+    )
+    .await;
+
+    let status_deleted = status.delete().await;
+
+    let record = JobRecord {
+        user_id: owner,
+        chat_id: message.chat().id(),
+        presets: preset_names,
+        photo_count,
+        image_bytes: progress.downloaded_bytes,
+        render_duration: progress.render_duration,
+        upload_duration: progress.upload_duration,
+        duration: started.elapsed(),
+        outcome: match &result {
+            Ok(()) => JobOutcome::Success,
+            Err(e) if e.downcast_ref::<Cancelled>().is_some() => JobOutcome::Cancelled,
+            Err(_) => JobOutcome::Error,
+        },
+    };
+    // the history is not important enough to fail the job
+    if let Err(e) = context.storage.jobs(&context.account_name).record(&record) {
+        warn!("Failed to record the job: {:?}", e);
+    }
+
+    match result {
+        Ok(()) => {
+            status_deleted?;
+            Ok(MessageResult::Ignore)
+        }
+        Err(e) if e.downcast_ref::<Cancelled>().is_some() => {
+            info!(
+                "The job was cancelled, deleting {} sent results",
+                progress.sent.len()
+            );
+            let sent = progress.sent.iter().map(|m| m.id()).collect::<Vec<_>>();
+            context.outbox.acquire(&message.chat()).await;
+            retry("delete_messages", || {
+                client.delete_messages(message.chat().pack(), &sent)
+            })
+            .await
+            .context("Deleting the results of a cancelled job")?;
+
+            Ok(MessageResult::Reply(Lang::ResultCancelled.into()))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// What a job has done so far, kept even if the job fails
+#[derive(Default)]
+struct JobProgress {
+    /// Results sent so far, deleted if the job gets cancelled
+    sent: Vec<Message>,
+    downloaded_bytes: usize,
+    render_duration: Duration,
+    upload_duration: Duration,
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn send_watermarked_photos(
+    message: &Message,
+    photos: Vec<Photo>,
+    presets: Vec<NamedPreset>,
+    client: &Client,
+    context: &BotContext,
+    cancel: &CancellationToken,
+    status: &mut StatusMessage,
+    progress: &mut JobProgress,
+) -> Result<()> {
+    let photos_data = download_photos(client, photos, Some(&mut *status), cancel).await?;
+    progress.downloaded_bytes = photos_data.iter().map(|p| p.len()).sum();
+
+    let render_started = Instant::now();
+    let results = render_presets(
+        &context.renderer,
+        photos_data,
+        presets,
+        Some(&mut *status),
+        cancel.clone(),
+    )
+    .await?;
+    progress.render_duration = render_started.elapsed();
+
+    let upload_started = Instant::now();
+    let upload_count = results.iter().map(|(_, r)| r.len()).sum::<usize>();
+    let mut uploaded = 0;
+
+    for (name, mut results) in results {
+        if results.len() == 1 {
+            // a single photo is sent as a regular message
+            cancel.check()?;
+            status
+                .update(Lang::StatusUploading(uploaded + 1, upload_count))
+                .await;
+            let result_file = upload_image(client, results.remove(0)).await?;
+            uploaded += 1;
+
+            context.outbox.acquire(&message.chat()).await;
+            let result = retry_send("reply", || {
+                message.reply(InputMessage::text(&name).photo(result_file.clone()))
+            })
+            .await
+            .context("Sending the result")?;
+            progress.sent.push(result);
+            progress.upload_duration = upload_started.elapsed();
+            continue;
+        }
+
+        let mut results = results.into_iter().peekable();
+        while results.peek().is_some() {
+            let mut result_files = Vec::new();
+            for result in results.by_ref().take(MAX_ALBUM_SIZE) {
+                cancel.check()?;
+                status
+                    .update(Lang::StatusUploading(uploaded + 1, upload_count))
+                    .await;
+                result_files.push(upload_image(client, result).await?);
+                uploaded += 1;
+            }
+
+            cancel.check()?;
+            context
+                .outbox
+                .acquire_album(&message.chat(), result_files.len())
+                .await;
+            let result = retry_send("send_album", || {
+                let album = result_files
+                    .iter()
+                    .enumerate()
+                    .map(|(i, result_file)| {
+                        // the caption of the first item is shown as the caption of the whole album
+                        let caption = if i == 0 { name.as_str() } else { "" };
+                        InputMedia::caption(caption)
+                            .photo(result_file.clone())
+                            .reply_to(Some(message.id()))
+                    })
+                    .collect();
+                client.send_album(message.chat().pack(), album)
+            })
+            .await
+            .context("Sending the result album")?;
+            progress.sent.extend(result.into_iter().flatten());
+            progress.upload_duration = upload_started.elapsed();
+        }
+    }
+
+    Ok(())
+}
+
+/// Downloads the photos, reporting the progress to the status message if there is one
+async fn download_photos(
+    client: &Client,
+    photos: Vec<Photo>,
+    mut status: Option<&mut StatusMessage>,
+    cancel: &CancellationToken,
+) -> Result<Vec<Vec<u8>>> {
+    // the largest size is the one that gets downloaded
+    let total_size = photos
+        .iter()
+        .map(|p| p.thumbs().iter().map(|t| t.size()).max().unwrap_or(0))
+        .sum::<usize>();
+    let mut downloaded = 0;
 
     // we are not limiting the photo size
     // Telegram already has reasonable limits, right?
 
-    let mut photo_data = Vec::new();
-    let mut download_iter = client.iter_download(&Downloadable::Media(Media::Photo(photo)));
-    while let Some(chunk) = download_iter
-        .next()
-        .await
-        .context("Downloading photo chunk")?
-    {
-        photo_data.extend_from_slice(&chunk);
+    let mut photos_data = Vec::new();
+    for photo in photos {
+        let mut photo_data = Vec::new();
+        let mut download_iter = client.iter_download(&Downloadable::Media(Media::Photo(photo)));
+        while let Some(chunk) = download_iter
+            .next()
+            .await
+            .context("Downloading photo chunk")?
+        {
+            cancel.check()?;
+            photo_data.extend_from_slice(&chunk);
+
+            downloaded += chunk.len();
+            if let Some(status) = status.as_deref_mut() {
+                let percent = (downloaded * 100).checked_div(total_size).unwrap_or(0);
+                status
+                    .update(Lang::StatusDownloading(
+                        downloaded / 1024,
+                        total_size / 1024,
+                        percent.min(100),
+                    ))
+                    .await;
+            }
+        }
+        photos_data.push(photo_data);
     }
 
-    let results = tokio::task::spawn_blocking(|| {
-        let image = image::load(Cursor::new(photo_data), image::ImageFormat::Jpeg)?.to_rgb8();
+    Ok(photos_data)
+}
+
+/// Applies each of the presets to the photos, returning the preset names along with the encoded results
+///
+/// The progress is reported to the status message if there is one
+async fn render_presets(
+    renderer: &Arc<MaskRenderer>,
+    photos_data: Vec<Vec<u8>>,
+    presets: Vec<NamedPreset>,
+    status: Option<&mut StatusMessage>,
+    cancel: CancellationToken,
+) -> Result<Vec<(String, Vec<Vec<u8>>)>> {
+    let render_count = photos_data.len() * presets.len();
+    let (progress_sender, mut progress) = tokio::sync::watch::channel(0);
+
+    let renderer = renderer.clone();
+    let mut task = tokio::task::spawn_blocking(move || {
+        let images = photos_data
+            .into_iter()
+            .map(|photo_data| {
+                Ok(image::load(Cursor::new(photo_data), image::ImageFormat::Jpeg)?.to_rgb8())
+            })
+            .collect::<Result<Vec<_>>>()?;
 
         let mut results = Vec::new();
-        for NamedPreset { name, preset } in mask_config.presets {
-            let mut image = image.clone();
-            crate::mask_generator::apply_mask(preset, &mut image);
+        for NamedPreset { name, preset } in presets {
+            let mut preset_results = Vec::new();
+            for image in &images {
+                cancel.check()?;
+                progress_sender.send_modify(|rendered| *rendered += 1);
 
-            let mut result = Vec::new();
-            image.write_to(&mut Cursor::new(&mut result), image::ImageFormat::Jpeg)?;
-            results.push((name, result));
+                let mut image = image.clone();
+                renderer.apply_mask_fast(preset.clone(), &mut image, &cancel)?;
+
+                let mut result = Vec::new();
+                image.write_to(&mut Cursor::new(&mut result), image::ImageFormat::Jpeg)?;
+                preset_results.push(result);
+            }
+            results.push((name, preset_results));
         }
 
         Ok::<_, anyhow::Error>(results)
-    })
-    .await??;
+    });
 
-    status_message
-        .delete()
-        .await
-        .context("Deleting status message")?;
+    let Some(status) = status else {
+        return task.await?;
+    };
 
-    for (name, result) in results {
-        let size = result.len();
-        let result_file = client
+    loop {
+        tokio::select! {
+            result = &mut task => return result?,
+            Ok(()) = progress.changed() => {
+                let rendering = *progress.borrow_and_update();
+                status.update(Lang::StatusRendering(rendering, render_count)).await;
+            }
+        }
+    }
+}
+
+async fn upload_image(client: &Client, image: Vec<u8>) -> Result<Uploaded> {
+    let size = image.len();
+    let image = &image;
+    retry("upload_stream", || async move {
+        client
             .upload_stream(
-                &mut Cursor::new(result),
+                &mut Cursor::new(image),
                 size,
                 "masked_image.jpg".to_string(),
             )
             .await
-            .context("Uploading masked image")?;
-
-        message
-            .reply(InputMessage::text(name).photo(result_file))
-            .await
-            .context("Sending the result")?;
-    }
-
-    Ok(MessageResult::Ignore)
+    })
+    .await
+    .context("Uploading masked image")
 }
@@ -0,0 +1,288 @@
+//! Scheduling of the outgoing messages to stay within the Telegram send limits
+//!
+//! Senders ask the [`Outbox`] for a permit before sending, editing or deleting a message, as these all count against the limits.
+//! Permits are handed out round-robin across chats, so one chat getting a lot of messages doesn't starve the others.
+
+use grammers_client::types::Chat;
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+
+/// At most `count` messages in any `period`
+#[derive(Debug, Copy, Clone)]
+struct Limit {
+    count: usize,
+    period: Duration,
+}
+
+/// Limits from the Telegram bot FAQ
+const GLOBAL_LIMIT: Limit = Limit {
+    count: 30,
+    period: Duration::from_secs(1),
+};
+const PRIVATE_CHAT_LIMIT: Limit = Limit {
+    count: 1,
+    period: Duration::from_secs(1),
+};
+const GROUP_CHAT_LIMIT: Limit = Limit {
+    count: 20,
+    period: Duration::from_secs(60),
+};
+
+/// Timestamps of the recent sends, used to check a [`Limit`]
+#[derive(Default)]
+struct SendWindow {
+    sends: VecDeque<Instant>,
+}
+
+impl SendWindow {
+    /// Returns the time when the next send is allowed
+    fn next_allowed(&mut self, limit: Limit, now: Instant) -> Instant {
+        while self
+            .sends
+            .front()
+            .is_some_and(|&sent| sent + limit.period <= now)
+        {
+            self.sends.pop_front();
+        }
+
+        if self.sends.len() < limit.count {
+            now
+        } else {
+            self.sends[self.sends.len() - limit.count] + limit.period
+        }
+    }
+
+    fn record(&mut self, now: Instant) {
+        self.sends.push_back(now);
+    }
+}
+
+struct ChatQueue<W> {
+    limit: Limit,
+    window: SendWindow,
+    waiters: VecDeque<W>,
+}
+
+/// The scheduling state, separate from the channels to be testable
+struct Scheduler<W> {
+    global: SendWindow,
+    chats: HashMap<i64, ChatQueue<W>>,
+    /// Chats with waiters, in the order they are served
+    ring: VecDeque<i64>,
+}
+
+impl<W> Scheduler<W> {
+    fn new() -> Self {
+        Self {
+            global: SendWindow::default(),
+            chats: HashMap::new(),
+            ring: VecDeque::new(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.ring.is_empty()
+    }
+
+    fn push(&mut self, chat_id: i64, limit: Limit, waiter: W) {
+        let chat = self.chats.entry(chat_id).or_insert_with(|| ChatQueue {
+            limit,
+            window: SendWindow::default(),
+            waiters: VecDeque::new(),
+        });
+        if chat.waiters.is_empty() {
+            self.ring.push_back(chat_id);
+        }
+        chat.waiters.push_back(waiter);
+    }
+
+    /// Returns the next waiter that is allowed to send, or the time when one will be
+    fn next(&mut self, now: Instant) -> Result<W, Option<Instant>> {
+        let global_allowed = self.global.next_allowed(GLOBAL_LIMIT, now);
+        if global_allowed > now {
+            return Err(Some(global_allowed));
+        }
+
+        let mut earliest = None::<Instant>;
+        for position in 0..self.ring.len() {
+            let chat_id = self.ring[position];
+            let chat = self.chats.get_mut(&chat_id).unwrap();
+
+            let allowed = chat.window.next_allowed(chat.limit, now);
+            if allowed > now {
+                earliest = Some(earliest.map_or(allowed, |e| e.min(allowed)));
+                continue;
+            }
+
+            let waiter = chat.waiters.pop_front().unwrap();
+            chat.window.record(now);
+            self.global.record(now);
+
+            // move the chat to the back of the ring, so that the other chats get served first
+            self.ring.remove(position);
+            if !chat.waiters.is_empty() {
+                self.ring.push_back(chat_id);
+            }
+
+            return Ok(waiter);
+        }
+
+        Err(earliest)
+    }
+
+    /// Forgets the chats that have no waiters and no recent sends
+    fn cleanup(&mut self, now: Instant) {
+        self.chats.retain(|_, chat| {
+            // this drops the sends that are too old to matter
+            chat.window.next_allowed(chat.limit, now);
+            !chat.waiters.is_empty() || !chat.window.sends.is_empty()
+        });
+    }
+}
+
+/// Hands out the permits to send messages
+///
+/// Clones share the same scheduler
+#[derive(Clone)]
+pub struct Outbox {
+    requests: mpsc::UnboundedSender<(i64, Limit, oneshot::Sender<()>)>,
+}
+
+impl Outbox {
+    /// Creates the outbox, spawning the task that schedules the sends
+    pub fn new() -> Self {
+        let (requests, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(run_scheduler(receiver));
+
+        Self { requests }
+    }
+
+    /// Waits until a message can be sent to the chat
+    pub async fn acquire(&self, chat: &Chat) {
+        let limit = match chat {
+            Chat::User(_) => PRIVATE_CHAT_LIMIT,
+            Chat::Group(_) | Chat::Channel(_) => GROUP_CHAT_LIMIT,
+        };
+
+        self.acquire_with_limit(chat.id(), limit).await;
+    }
+
+    /// Waits until an album of `count` media can be sent to the chat
+    ///
+    /// Telegram counts each media of an album as a separate message
+    pub async fn acquire_album(&self, chat: &Chat, count: usize) {
+        for _ in 0..count {
+            self.acquire(chat).await;
+        }
+    }
+
+    /// Waits until a message can be sent to the user in private, when only the user id is known
+    pub async fn acquire_private(&self, user_id: i64) {
+        self.acquire_with_limit(user_id, PRIVATE_CHAT_LIMIT).await;
+    }
+
+    async fn acquire_with_limit(&self, chat_id: i64, limit: Limit) {
+        let (sender, receiver) = oneshot::channel();
+        if self.requests.send((chat_id, limit, sender)).is_err() {
+            // the scheduler is gone, there's nothing to wait for
+            return;
+        }
+        let _ = receiver.await;
+    }
+}
+
+async fn run_scheduler(mut requests: mpsc::UnboundedReceiver<(i64, Limit, oneshot::Sender<()>)>) {
+    let mut scheduler = Scheduler::new();
+
+    loop {
+        while let Ok((chat_id, limit, waiter)) = requests.try_recv() {
+            scheduler.push(chat_id, limit, waiter);
+        }
+
+        if scheduler.is_empty() {
+            scheduler.cleanup(Instant::now());
+            match requests.recv().await {
+                Some((chat_id, limit, waiter)) => scheduler.push(chat_id, limit, waiter),
+                None => return,
+            }
+            continue;
+        }
+
+        match scheduler.next(Instant::now()) {
+            Ok(waiter) => {
+                // the waiter might have given up already, that's fine
+                let _ = waiter.send(());
+            }
+            Err(wait_until) => {
+                let wait_until = wait_until.unwrap_or_else(Instant::now);
+                tokio::select! {
+                    _ = tokio::time::sleep_until(wait_until) => {}
+                    request = requests.recv() => match request {
+                        Some((chat_id, limit, waiter)) => scheduler.push(chat_id, limit, waiter),
+                        None => return,
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Limit, Scheduler, GLOBAL_LIMIT, GROUP_CHAT_LIMIT, PRIVATE_CHAT_LIMIT};
+    use std::time::Duration;
+    use tokio::time::Instant;
+
+    #[test]
+    fn per_chat_limit() {
+        let now = Instant::now();
+        let mut scheduler = Scheduler::new();
+        scheduler.push(1, PRIVATE_CHAT_LIMIT, "a1");
+        scheduler.push(1, PRIVATE_CHAT_LIMIT, "a2");
+
+        assert_eq!(scheduler.next(now), Ok("a1"));
+        assert_eq!(
+            scheduler.next(now),
+            Err(Some(now + PRIVATE_CHAT_LIMIT.period))
+        );
+        assert_eq!(scheduler.next(now + PRIVATE_CHAT_LIMIT.period), Ok("a2"));
+    }
+
+    #[test]
+    fn round_robin_between_chats() {
+        let now = Instant::now();
+        let mut scheduler = Scheduler::new();
+        for waiter in ["a1", "a2", "a3"] {
+            scheduler.push(1, GROUP_CHAT_LIMIT, waiter);
+        }
+        scheduler.push(2, GROUP_CHAT_LIMIT, "b1");
+        scheduler.push(2, GROUP_CHAT_LIMIT, "b2");
+
+        let order = std::iter::from_fn(|| scheduler.next(now).ok()).collect::<Vec<_>>();
+        assert_eq!(order, ["a1", "b1", "a2", "b2", "a3"]);
+    }
+
+    #[test]
+    fn global_limit() {
+        let now = Instant::now();
+        let mut scheduler = Scheduler::new();
+        let unlimited = Limit {
+            count: usize::MAX,
+            period: Duration::from_secs(1),
+        };
+        for i in 0..GLOBAL_LIMIT.count + 1 {
+            scheduler.push(i as i64, unlimited, i);
+        }
+
+        for i in 0..GLOBAL_LIMIT.count {
+            assert_eq!(scheduler.next(now), Ok(i));
+        }
+        assert_eq!(scheduler.next(now), Err(Some(now + GLOBAL_LIMIT.period)));
+        assert_eq!(
+            scheduler.next(now + GLOBAL_LIMIT.period),
+            Ok(GLOBAL_LIMIT.count)
+        );
+    }
+}
@@ -0,0 +1,343 @@
+//! Presets available to an account, both from the config and managed from the chat
+
+use crate::cancellation::CancellationToken;
+use crate::config;
+use crate::config::NamedPreset;
+use crate::mask_generator::MaskRenderer;
+use crate::storage::{Storage, StoredPreset, UserPreset};
+use anyhow::Result;
+use image::{Rgb, RgbImage};
+use std::io::Cursor;
+use std::sync::{Arc, Mutex};
+use tracing::warn;
+
+/// How many personal presets a user can have
+pub const MAX_USER_PRESETS: usize = 5;
+/// Longer texts don't fit on the image anyway
+pub const MAX_USER_PRESET_TEXT_LEN: usize = 64;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PresetSource {
+    Config,
+    /// Added by a superuser from the chat
+    Chat,
+}
+
+pub struct PresetStore {
+    /// Presets from the config, already filtered for the account
+    config: Vec<NamedPreset>,
+    storage: Arc<Storage>,
+    account_name: String,
+    /// Presets added from the chat, parsed once and dropped on every change to them
+    chat_presets: Mutex<Option<Arc<Vec<NamedPreset>>>>,
+}
+
+impl PresetStore {
+    pub fn new(config: Vec<NamedPreset>, storage: Arc<Storage>, account_name: &str) -> Self {
+        Self {
+            config,
+            storage,
+            account_name: account_name.to_string(),
+            chat_presets: Mutex::new(None),
+        }
+    }
+
+    /// All the presets along with where they come from, the config ones first
+    pub fn list(&self) -> Result<Vec<(NamedPreset, PresetSource)>> {
+        let chat_presets = self.chat_presets()?;
+        let presets = self
+            .config
+            .iter()
+            .map(|p| (p.clone(), PresetSource::Config))
+            .chain(chat_presets.iter().map(|p| (p.clone(), PresetSource::Chat)))
+            .collect();
+
+        Ok(presets)
+    }
+
+    /// The presets added from the chat, loaded from the storage if they changed since the last time
+    fn chat_presets(&self) -> Result<Arc<Vec<NamedPreset>>> {
+        let mut cache = self.chat_presets.lock().unwrap();
+        if let Some(presets) = &*cache {
+            return Ok(presets.clone());
+        }
+
+        let presets = Arc::new(self.load_chat_presets()?);
+        *cache = Some(presets.clone());
+        Ok(presets)
+    }
+
+    /// Parses the stored presets, skipping the ones that no longer parse (e.g. after a format change)
+    fn load_chat_presets(&self) -> Result<Vec<NamedPreset>> {
+        let mut presets = Vec::new();
+        for stored in self.storage.presets(&self.account_name).list()? {
+            match config::parse_preset(&stored.definition) {
+                Ok(preset) => presets.push(NamedPreset {
+                    name: stored.name,
+                    preset,
+                }),
+                Err(e) => warn!("Skipping invalid stored preset `{}`: {:?}", stored.name, e),
+            }
+        }
+
+        Ok(presets)
+    }
+
+    fn invalidate(&self) {
+        *self.chat_presets.lock().unwrap() = None;
+    }
+
+    pub fn all(&self) -> Result<Vec<NamedPreset>> {
+        Ok(self.list()?.into_iter().map(|(p, _)| p).collect())
+    }
+
+    /// The global presets along with the personal presets the user has turned on
+    pub fn for_user(&self, user_id: i64) -> Result<Vec<NamedPreset>> {
+        let mut presets = self.all()?;
+        let active = self
+            .user_presets(user_id)?
+            .into_iter()
+            .filter(|p| p.active)
+            .collect::<Vec<_>>();
+        let personal = resolve_user_presets(&presets, &active);
+        presets.extend(personal);
+
+        Ok(presets)
+    }
+
+    /// Personal presets of the user, resolved against the global presets
+    pub fn personal_presets(
+        &self,
+        user_id: i64,
+        templates: &[NamedPreset],
+    ) -> Result<Vec<NamedPreset>> {
+        Ok(resolve_user_presets(
+            templates,
+            &self.user_presets(user_id)?,
+        ))
+    }
+
+    /// Global presets that can be used as a template for a personal preset, i.e. the text ones
+    pub fn templates(&self) -> Result<Vec<NamedPreset>> {
+        Ok(self
+            .all()?
+            .into_iter()
+            .filter(|p| p.preset.with_text("").is_some())
+            .collect())
+    }
+
+    pub fn user_presets(&self, user_id: i64) -> Result<Vec<UserPreset>> {
+        self.storage.user_presets(&self.account_name).list(user_id)
+    }
+
+    /// Adds a personal preset, returning `false` if the user already has a preset with this name
+    pub fn insert_user_preset(&self, user_id: i64, preset: &UserPreset) -> Result<bool> {
+        self.storage
+            .user_presets(&self.account_name)
+            .insert(user_id, preset)
+    }
+
+    /// Turns a personal preset on or off, returning `false` if the user has no such preset
+    pub fn set_user_preset_active(&self, user_id: i64, name: &str, active: bool) -> Result<bool> {
+        self.storage
+            .user_presets(&self.account_name)
+            .set_active(user_id, name, active)
+    }
+
+    /// Deletes a personal preset, returning `false` if the user has no such preset
+    pub fn delete_user_preset(&self, user_id: i64, name: &str) -> Result<bool> {
+        self.storage
+            .user_presets(&self.account_name)
+            .delete(user_id, name)
+    }
+
+    pub fn get(&self, name: &str) -> Result<Option<(NamedPreset, PresetSource)>> {
+        Ok(self.list()?.into_iter().find(|(p, _)| p.name == name))
+    }
+
+    pub fn is_from_config(&self, name: &str) -> bool {
+        self.config.iter().any(|p| p.name == name)
+    }
+
+    /// Adds a preset, returning `false` if a preset with this name already exists
+    ///
+    /// The definition is expected to be already validated
+    pub fn insert(&self, name: &str, definition: &str, created_by: i64) -> Result<bool> {
+        if self.is_from_config(name) {
+            return Ok(false);
+        }
+        let inserted = self
+            .storage
+            .presets(&self.account_name)
+            .insert(&stored(name, definition), created_by)?;
+        self.invalidate();
+        Ok(inserted)
+    }
+
+    /// Replaces the definition of a preset added from the chat, returning `false` if there's no such preset
+    pub fn update(&self, name: &str, definition: &str) -> Result<bool> {
+        let updated = self
+            .storage
+            .presets(&self.account_name)
+            .update(&stored(name, definition))?;
+        self.invalidate();
+        Ok(updated)
+    }
+
+    /// Deletes a preset added from the chat, returning `false` if there's no such preset
+    pub fn delete(&self, name: &str) -> Result<bool> {
+        let deleted = self.storage.presets(&self.account_name).delete(name)?;
+        self.invalidate();
+        Ok(deleted)
+    }
+}
+
+/// Applies the personal presets to their templates
+///
+/// The names are only checked against the global presets when the personal preset is added,
+/// so a global preset added later under the same name takes precedence over it
+fn resolve_user_presets(templates: &[NamedPreset], presets: &[UserPreset]) -> Vec<NamedPreset> {
+    presets
+        .iter()
+        .filter(|preset| {
+            let clashes = templates.iter().any(|t| t.name == preset.name);
+            if clashes {
+                warn!(
+                    "Skipping the personal preset `{}`, as there's a global preset with the same name",
+                    preset.name
+                );
+            }
+            !clashes
+        })
+        .filter_map(|preset| resolve_user_preset(templates, preset))
+        .collect()
+}
+
+/// Applies the text of the personal preset to its template
+///
+/// Returns `None` if the template is gone or is not a text one anymore
+pub fn resolve_user_preset(templates: &[NamedPreset], preset: &UserPreset) -> Option<NamedPreset> {
+    let template = templates.iter().find(|t| t.name == preset.template)?;
+
+    Some(NamedPreset {
+        name: preset.name.clone(),
+        preset: template.preset.with_text(&preset.text)?,
+    })
+}
+
+fn stored(name: &str, definition: &str) -> StoredPreset {
+    StoredPreset {
+        name: name.to_string(),
+        definition: definition.to_string(),
+    }
+}
+
+/// Size of the image the previews are rendered on
+const PREVIEW_WIDTH: u32 = 1280;
+const PREVIEW_HEIGHT: u32 = 960;
+
+/// Renders the preset over a sample image, returning it encoded as JPEG
+pub async fn render_preview(renderer: &Arc<MaskRenderer>, preset: NamedPreset) -> Result<Vec<u8>> {
+    let renderer = renderer.clone();
+    tokio::task::spawn_blocking(move || {
+        // a diagonal gradient, to show how the preset looks over both dark and light areas
+        let mut image = RgbImage::from_fn(PREVIEW_WIDTH, PREVIEW_HEIGHT, |x, y| {
+            let t = (x + y) as f32 / (PREVIEW_WIDTH + PREVIEW_HEIGHT) as f32;
+            Rgb([
+                (t * 255.0) as u8,
+                (t * 160.0) as u8 + 40,
+                200 - (t * 150.0) as u8,
+            ])
+        });
+        renderer.apply_mask_fast(preset.preset, &mut image, &CancellationToken::default())?;
+
+        let mut result = Vec::new();
+        image.write_to(&mut Cursor::new(&mut result), image::ImageFormat::Jpeg)?;
+        Ok(result)
+    })
+    .await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PresetSource, PresetStore};
+    use crate::config::{parse_preset, NamedPreset};
+    use crate::storage::{Storage, UserPreset};
+    use std::sync::Arc;
+
+    fn stripes(count: u32) -> String {
+        format!(
+            "alpha: 32\ncontent:\n  type: Stripes\n  color1: '#ff0000'\n  color2: '#00ff00'\n  stripe_count: {}\n",
+            count
+        )
+    }
+
+    #[test]
+    fn chat_presets_follow_changes() {
+        let store = PresetStore::new(
+            Vec::new(),
+            Arc::new(Storage::open_in_memory().unwrap()),
+            "main",
+        );
+        let preset = |store: &PresetStore| {
+            let (preset, source) = store.get("stripes").unwrap()?;
+            assert_eq!(source, PresetSource::Chat);
+            Some(format!("{:?}", preset.preset))
+        };
+        assert_eq!(preset(&store), None);
+
+        assert!(store.insert("stripes", &stripes(4), 1).unwrap());
+        assert!(preset(&store).unwrap().contains("stripe_count: 4"));
+
+        assert!(store.update("stripes", &stripes(8)).unwrap());
+        assert!(preset(&store).unwrap().contains("stripe_count: 8"));
+
+        assert!(store.delete("stripes").unwrap());
+        assert_eq!(preset(&store), None);
+    }
+
+    #[test]
+    fn personal_presets_for_user() {
+        let text = parse_preset(
+            "alpha: 32\ncontent:\n  type: Text\n  text: global\n  font: Comic Sans MS\n  color: '#ffffff'\n  size: 5\n  rotation: 45\n  row_slide: 1\n  offset_x: -30\n  stride_x: 30\n  offset_y: -20\n  stride_y: 20\n",
+        )
+        .unwrap();
+        let store = PresetStore::new(
+            vec![NamedPreset {
+                name: "global".to_string(),
+                preset: text,
+            }],
+            Arc::new(Storage::open_in_memory().unwrap()),
+            "main",
+        );
+        for (name, active) in [("on", true), ("off", false), ("clash", true)] {
+            let preset = UserPreset {
+                name: name.to_string(),
+                template: "global".to_string(),
+                text: name.to_string(),
+                active,
+            };
+            assert!(store.insert_user_preset(1, &preset).unwrap());
+        }
+        let names =
+            |presets: Vec<NamedPreset>| presets.into_iter().map(|p| p.name).collect::<Vec<_>>();
+
+        assert_eq!(names(store.for_user(1).unwrap()), ["global", "clash", "on"]);
+        assert_eq!(names(store.for_user(2).unwrap()), ["global"]);
+
+        // a global preset added later takes the name over, instead of giving two results with the same name
+        assert!(store.insert("clash", &stripes(4), 2).unwrap());
+        assert_eq!(names(store.for_user(1).unwrap()), ["global", "clash", "on"]);
+        let templates = store.all().unwrap();
+        assert_eq!(
+            names(store.personal_presets(1, &templates).unwrap()),
+            ["off", "on"]
+        );
+
+        assert!(store.set_user_preset_active(1, "off", true).unwrap());
+        assert_eq!(
+            names(store.for_user(1).unwrap()),
+            ["global", "clash", "off", "on"]
+        );
+    }
+}
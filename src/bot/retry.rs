@@ -0,0 +1,277 @@
+//! Retrying of the Telegram requests on flood waits and transient errors
+
+use grammers_client::InvocationError;
+use std::future::Future;
+use std::time::Duration;
+use tracing::{field, info_span, warn, Instrument as _};
+
+const MAX_ATTEMPTS: u32 = 5;
+
+/// Flood waits longer than that are not worth waiting for, the user would give up anyway
+const MAX_FLOOD_WAIT: Duration = Duration::from_secs(5 * 60);
+
+const BACKOFF_BASE: Duration = Duration::from_secs(1);
+const BACKOFF_MAX: Duration = Duration::from_secs(30);
+
+/// An error that may go away if the request is retried
+pub trait RetryableError: std::fmt::Display {
+    /// Returns how long to wait before the next attempt, or `None` if the request should not be retried
+    fn retry_after(&self, attempt: u32) -> Option<Duration>;
+
+    /// Same as [`RetryableError::retry_after`], but only for the errors that guarantee that the request wasn't executed
+    ///
+    /// A lost connection may happen after Telegram has already executed the request,
+    /// so repeating a request that sends something would send it twice
+    fn retry_unexecuted_after(&self, attempt: u32) -> Option<Duration>;
+}
+
+fn backoff(attempt: u32) -> Duration {
+    BACKOFF_BASE
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(BACKOFF_MAX)
+}
+
+/// How long to wait on an RPC error, given its code and its name and value as parsed by grammers
+fn rpc_retry_after(code: i32, name: &str, value: Option<u32>, attempt: u32) -> Option<Duration> {
+    match name {
+        // grammers parses FLOOD_WAIT_X into the name and the value
+        "FLOOD_WAIT" => {
+            let wait = Duration::from_secs(value.unwrap_or(1).into());
+            (wait <= MAX_FLOOD_WAIT).then_some(wait)
+        }
+        // internal server errors
+        _ if code >= 500 || code == -500 => Some(backoff(attempt)),
+        _ => None,
+    }
+}
+
+/// Same as [`rpc_retry_after`], but only for the errors that reject the request before executing it
+fn rpc_retry_unexecuted_after(
+    code: i32,
+    name: &str,
+    value: Option<u32>,
+    attempt: u32,
+) -> Option<Duration> {
+    match name {
+        "FLOOD_WAIT" => rpc_retry_after(code, name, value, attempt),
+        _ => None,
+    }
+}
+
+impl RetryableError for InvocationError {
+    fn retry_after(&self, attempt: u32) -> Option<Duration> {
+        match self {
+            InvocationError::Rpc(e) => rpc_retry_after(e.code, &e.name, e.value, attempt),
+            InvocationError::Io(e) => e.retry_after(attempt),
+            InvocationError::Dropped => Some(backoff(attempt)),
+            _ => None,
+        }
+    }
+
+    fn retry_unexecuted_after(&self, attempt: u32) -> Option<Duration> {
+        match self {
+            InvocationError::Rpc(e) => {
+                rpc_retry_unexecuted_after(e.code, &e.name, e.value, attempt)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl RetryableError for std::io::Error {
+    fn retry_after(&self, attempt: u32) -> Option<Duration> {
+        use std::io::ErrorKind;
+
+        // the other kinds, like a missing file or invalid data, fail the same way on every attempt
+        matches!(
+            self.kind(),
+            ErrorKind::ConnectionReset
+                | ErrorKind::ConnectionAborted
+                | ErrorKind::ConnectionRefused
+                | ErrorKind::NotConnected
+                | ErrorKind::BrokenPipe
+                | ErrorKind::TimedOut
+                | ErrorKind::Interrupted
+                | ErrorKind::UnexpectedEof
+        )
+        .then(|| backoff(attempt))
+    }
+
+    fn retry_unexecuted_after(&self, _attempt: u32) -> Option<Duration> {
+        None
+    }
+}
+
+/// Runs the request, retrying it if it fails with a retryable error
+///
+/// Each request gets its own span, with the number of retries recorded in it.
+/// The request has to be safe to repeat, use [`retry_send`] for sending messages
+pub async fn retry<T, E, F, Fut>(request: &str, f: F) -> Result<T, E>
+where
+    E: RetryableError,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    retry_with(request, f, E::retry_after).await
+}
+
+/// Same as [`retry`], but for the requests that must not be executed twice, like sending a message
+///
+/// Only retries if the request surely wasn't executed, so a lost connection fails the request
+pub async fn retry_send<T, E, F, Fut>(request: &str, f: F) -> Result<T, E>
+where
+    E: RetryableError,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    retry_with(request, f, E::retry_unexecuted_after).await
+}
+
+async fn retry_with<T, E, F, Fut>(
+    request: &str,
+    mut f: F,
+    retry_after: fn(&E, u32) -> Option<Duration>,
+) -> Result<T, E>
+where
+    E: RetryableError,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let span = info_span!("telegram_request", request, retries = field::Empty);
+
+    async {
+        let mut attempt = 0;
+        loop {
+            let error = match f().await {
+                Ok(result) => return Ok(result),
+                Err(e) => e,
+            };
+
+            attempt += 1;
+            if attempt >= MAX_ATTEMPTS {
+                return Err(error);
+            }
+            let Some(wait) = retry_after(&error, attempt - 1) else {
+                return Err(error);
+            };
+
+            tracing::Span::current().record("retries", attempt);
+            warn!(
+                "Request failed (attempt {}/{}), retrying in {:?}: {}",
+                attempt, MAX_ATTEMPTS, wait, error
+            );
+            tokio::time::sleep(wait).await;
+        }
+    }
+    .instrument(span)
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        backoff, retry, retry_send, rpc_retry_after, rpc_retry_unexecuted_after, RetryableError,
+    };
+    use std::io::{Error, ErrorKind};
+    use std::time::Duration;
+
+    /// Stands for the RPC errors, which can't be constructed outside of grammers
+    #[derive(Debug)]
+    struct Rpc(i32, &'static str, Option<u32>);
+
+    impl std::fmt::Display for Rpc {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(f, "{} {}", self.0, self.1)
+        }
+    }
+
+    impl RetryableError for Rpc {
+        fn retry_after(&self, attempt: u32) -> Option<Duration> {
+            rpc_retry_after(self.0, self.1, self.2, attempt)
+        }
+
+        fn retry_unexecuted_after(&self, attempt: u32) -> Option<Duration> {
+            rpc_retry_unexecuted_after(self.0, self.1, self.2, attempt)
+        }
+    }
+
+    const fn secs(secs: u64) -> Option<Duration> {
+        Some(Duration::from_secs(secs))
+    }
+
+    #[test]
+    fn flood_wait() {
+        assert_eq!(Rpc(420, "FLOOD_WAIT", Some(30)).retry_after(0), secs(30));
+        assert_eq!(Rpc(420, "FLOOD_WAIT", Some(300)).retry_after(3), secs(300));
+        // longer than the cap
+        assert_eq!(Rpc(420, "FLOOD_WAIT", Some(301)).retry_after(0), None);
+    }
+
+    #[test]
+    fn server_errors() {
+        assert_eq!(Rpc(500, "INTERNAL", None).retry_after(0), secs(1));
+        assert_eq!(
+            Rpc(-500, "NO_WORKERS_RUNNING", None).retry_after(1),
+            secs(2)
+        );
+        assert_eq!(Rpc(400, "PEER_ID_INVALID", None).retry_after(0), None);
+        assert_eq!(Rpc(403, "CHAT_WRITE_FORBIDDEN", None).retry_after(0), None);
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        assert_eq!(Some(backoff(0)), secs(1));
+        assert_eq!(Some(backoff(4)), secs(16));
+        assert_eq!(Some(backoff(5)), secs(30));
+        assert_eq!(Some(backoff(100)), secs(30));
+    }
+
+    #[test]
+    fn io_errors() {
+        assert_eq!(
+            Error::from(ErrorKind::ConnectionReset).retry_after(0),
+            secs(1)
+        );
+        assert_eq!(Error::from(ErrorKind::TimedOut).retry_after(2), secs(4));
+        assert_eq!(Error::from(ErrorKind::NotFound).retry_after(0), None);
+        assert_eq!(Error::from(ErrorKind::InvalidData).retry_after(0), None);
+        // the request may have reached Telegram before the connection broke
+        let reset = Error::from(ErrorKind::ConnectionReset);
+        assert_eq!(reset.retry_unexecuted_after(0), None);
+    }
+
+    /// A request that fails with the errors in turn and then succeeds, counting its attempts
+    fn failing(
+        errors: Vec<Rpc>,
+        attempts: &mut usize,
+    ) -> impl FnMut() -> std::future::Ready<Result<(), Rpc>> + '_ {
+        let mut errors = errors.into_iter();
+        move || {
+            *attempts += 1;
+            std::future::ready(errors.next().map_or(Ok(()), Err))
+        }
+    }
+
+    #[tokio::test]
+    async fn retry_send_only_on_flood_wait() {
+        let flood_wait = || Rpc(420, "FLOOD_WAIT", Some(0));
+
+        let mut attempts = 0;
+        let result = retry_send("send", failing(vec![flood_wait()], &mut attempts)).await;
+        assert!(result.is_ok());
+        assert_eq!(attempts, 2);
+
+        let mut attempts = 0;
+        let server_error = Rpc(500, "INTERNAL", None);
+        let result = retry_send("send", failing(vec![server_error], &mut attempts)).await;
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+
+        // gives up after the last attempt
+        let mut attempts = 0;
+        let errors = (0..10).map(|_| flood_wait()).collect();
+        let result = retry("get", failing(errors, &mut attempts)).await;
+        assert!(result.is_err());
+        assert_eq!(attempts, 5);
+    }
+}
@@ -0,0 +1,66 @@
+//! The usage statistics report for the superusers
+
+use crate::bot::lang::Lang;
+use crate::storage::{now, JobTotals, Storage};
+use anyhow::Result;
+use std::time::Duration;
+
+const DAY: i64 = 24 * 60 * 60;
+const WEEK: i64 = 7 * DAY;
+
+/// How many users and presets to list
+const TOP_COUNT: usize = 5;
+
+pub fn stats_report(storage: &Storage, account_name: &str) -> Result<Lang> {
+    let jobs = storage.jobs(account_name);
+    let now = now();
+
+    let day = period_summary(jobs.totals(now - DAY)?);
+    let week = period_summary(jobs.totals(now - WEEK)?);
+
+    let users = jobs
+        .top_users(now - WEEK, TOP_COUNT)?
+        .into_iter()
+        .map(|u| match u.username {
+            Some(username) => format!("@{} ({}): {}", username, u.user_id, u.jobs),
+            None => format!("{}: {}", u.user_id, u.jobs),
+        })
+        .collect::<Vec<_>>();
+    let presets = jobs
+        .top_presets(now - WEEK, TOP_COUNT)?
+        .into_iter()
+        .map(|p| format!("{}: {}", p.name, p.jobs))
+        .collect::<Vec<_>>();
+
+    Ok(Lang::StatsReport(day, week, list(users), list(presets)))
+}
+
+fn period_summary(totals: JobTotals) -> String {
+    if totals.jobs == 0 {
+        return Lang::StatsNoJobs.to_string();
+    }
+
+    let seconds = |d: Option<Duration>| {
+        d.map(|d| format!("{:.1}", d.as_secs_f64()))
+            .unwrap_or_else(|| "-".to_string())
+    };
+
+    Lang::StatsPeriod(
+        totals.jobs,
+        totals.photos,
+        totals.failed * 100 / totals.jobs,
+        totals.cancelled,
+        seconds(totals.average_duration),
+        seconds(totals.average_render_duration),
+        seconds(totals.average_upload_duration),
+    )
+    .to_string()
+}
+
+fn list(items: Vec<String>) -> String {
+    if items.is_empty() {
+        "-".to_string()
+    } else {
+        items.join("\n")
+    }
+}
@@ -0,0 +1,84 @@
+//! The status message, which is edited to report the progress of a job
+
+use crate::bot::lang::Lang;
+use crate::bot::outbox::Outbox;
+use crate::bot::retry::{retry, retry_send};
+use anyhow::{Context as _, Result};
+use grammers_client::types::Message;
+use grammers_client::{button, reply_markup, InputMessage};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Minimal interval between the edits of the status message
+///
+/// Telegram starts to throttle the edits if they are done too often
+const MIN_EDIT_INTERVAL: Duration = Duration::from_secs(3);
+
+pub struct StatusMessage {
+    message: Message,
+    /// Edits and deletions count against the limits of the chat as well
+    outbox: Outbox,
+    /// Data of the cancel button attached to the message
+    cancel_button_data: String,
+    last_edit: Instant,
+    last_text: String,
+}
+
+impl StatusMessage {
+    /// Sends the initial status message as a reply to the message
+    pub async fn send(
+        reply_to: &Message,
+        outbox: &Outbox,
+        cancel_button_data: String,
+    ) -> Result<Self> {
+        outbox.acquire(&reply_to.chat()).await;
+        let last_text = Lang::StatusWorking.to_string();
+        let message = retry_send("reply", || {
+            reply_to.reply(with_cancel_button(&last_text, &cancel_button_data))
+        })
+        .await
+        .context("Sending status message")?;
+
+        Ok(Self {
+            message,
+            outbox: outbox.clone(),
+            cancel_button_data,
+            last_edit: Instant::now(),
+            last_text,
+        })
+    }
+
+    /// Edits the status message to show the new status
+    ///
+    /// The update is dropped if the previous edit was too recent.
+    /// Failing to edit the status is not fatal for the job, so the errors are only logged and not retried
+    pub async fn update(&mut self, status: Lang) {
+        let text = status.to_string();
+        if text == self.last_text || self.last_edit.elapsed() < MIN_EDIT_INTERVAL {
+            return;
+        }
+
+        self.last_edit = Instant::now();
+        let message = with_cancel_button(&text, &self.cancel_button_data);
+        self.outbox.acquire(&self.message.chat()).await;
+        if let Err(e) = self.message.edit(message).await {
+            warn!("Failed to edit the status message: {}", e);
+            return;
+        }
+        self.last_text = text;
+    }
+
+    pub async fn delete(self) -> Result<()> {
+        self.outbox.acquire(&self.message.chat()).await;
+        retry("delete", || self.message.delete())
+            .await
+            .context("Deleting status message")
+    }
+}
+
+fn with_cancel_button(text: &str, cancel_button_data: &str) -> InputMessage {
+    InputMessage::text(text).reply_markup(&reply_markup::inline(vec![vec![button::inline(
+        Lang::ButtonCancel.to_string(),
+        cancel_button_data.as_bytes().to_vec(),
+    )]]))
+}
@@ -0,0 +1,35 @@
+//! Cooperative cancellation of long-running jobs
+
+use displaydoc::Display;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// A flag shared between a job and whoever is allowed to cancel it
+///
+/// The job is expected to periodically call [`CancellationToken::check`] and bail out when it fails
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn check(&self) -> Result<(), Cancelled> {
+        if self.is_cancelled() {
+            Err(Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// The job was cancelled
+#[derive(Debug, Display)]
+pub struct Cancelled;
+
+impl std::error::Error for Cancelled {}
@@ -1,10 +1,28 @@
-use anyhow::{Context as _, Result};
+use anyhow::{bail, Context as _, Result};
 use serde::Deserialize;
+use std::collections::HashSet;
+use std::fmt;
+
+mod presets;
 
 #[derive(Deserialize, Clone, Debug)]
 pub struct Config {
-    pub telegram: Telegram,
+    /// A shorthand for a single account. When set, it's moved into `accounts` under the name `default`
+    #[serde(default)]
+    pub telegram: Option<Telegram>,
+    #[serde(default)]
+    pub accounts: Vec<Account>,
     pub masks: Mask,
+    #[serde(default)]
+    pub access: Access,
+    /// Settings for group chats
+    #[serde(default)]
+    pub chats: Vec<ChatConfig>,
+    /// Channels to watermark the new posts in
+    #[serde(default)]
+    pub channels: Vec<ChannelConfig>,
+    #[serde(default)]
+    pub storage: Storage,
 }
 
 impl Config {
@@ -37,20 +55,221 @@ impl Config {
             .build()
             .context("Building the config file")?;
 
-        config
+        let config: Config = config
             .try_deserialize()
-            .context("Deserializing config structure failed")
+            .context("Deserializing config structure failed")?;
+
+        config.resolve()
+    }
+
+    /// Fills in the shorthands and the defaults that depend on the other fields, and validates the result
+    fn resolve(mut self) -> Result<Config> {
+        if let Some(telegram) = self.telegram.take() {
+            self.accounts.insert(
+                0,
+                Account {
+                    name: "default".to_string(),
+                    telegram,
+                    presets: None,
+                    access: None,
+                },
+            );
+        }
+
+        // each account would post its own copy otherwise
+        if let Some(first) = self.accounts.first() {
+            for channel in &mut self.channels {
+                channel.account.get_or_insert_with(|| first.name.clone());
+            }
+        }
+
+        self.validate()?;
+
+        Ok(self)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.accounts.is_empty() {
+            bail!("No telegram accounts configured, please set either `telegram` or `accounts`");
+        }
+
+        let mut account_names = HashSet::new();
+        for account in &self.accounts {
+            if !account_names.insert(account.name.as_str()) {
+                bail!("Duplicate account name `{}`", account.name);
+            }
+            for preset in account.presets.iter().flatten() {
+                if !self.masks.presets.iter().any(|p| &p.name == preset) {
+                    bail!(
+                        "Account `{}` references an unknown preset `{}`",
+                        account.name,
+                        preset
+                    );
+                }
+            }
+        }
+
+        for chat in &self.chats {
+            for preset in chat.presets.iter().flatten() {
+                if !self.masks.presets.iter().any(|p| &p.name == preset) {
+                    bail!("Chat {} references an unknown preset `{}`", chat.id, preset);
+                }
+            }
+        }
+
+        for channel in &self.channels {
+            let account = self
+                .accounts
+                .iter()
+                .find(|a| channel.account.as_ref() == Some(&a.name))
+                .with_context(|| {
+                    format!(
+                        "Channel {} references an unknown account `{}`",
+                        channel.id,
+                        channel.account.as_deref().unwrap_or_default()
+                    )
+                })?;
+            if !account
+                .presets(&self.masks)
+                .iter()
+                .any(|p| p.name == channel.preset)
+            {
+                bail!(
+                    "Channel {} references a preset `{}` that account `{}` doesn't offer",
+                    channel.id,
+                    channel.preset,
+                    account.name
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct Account {
+    pub name: String,
+    pub telegram: Telegram,
+    /// Names of the presets this account offers. All the presets are offered if not set
+    #[serde(default)]
+    pub presets: Option<Vec<String>>,
+    /// Overrides the global access policy for this account
+    #[serde(default)]
+    pub access: Option<Access>,
+}
+
+impl Account {
+    pub fn presets(&self, mask: &Mask) -> Vec<NamedPreset> {
+        match &self.presets {
+            None => mask.presets.clone(),
+            Some(names) => mask
+                .presets
+                .iter()
+                .filter(|p| names.contains(&p.name))
+                .cloned()
+                .collect(),
+        }
     }
 }
 
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct Access {
+    /// Users that are allowed to do anything
+    #[serde(default)]
+    pub superusers: Vec<i64>,
+    /// When set, only these users (and the superusers) can use the bot
+    #[serde(default)]
+    pub allowed_users: Option<Vec<i64>>,
+}
+
+impl Access {
+    pub fn is_superuser(&self, user_id: i64) -> bool {
+        self.superusers.contains(&user_id)
+    }
+
+    pub fn is_allowed(&self, user_id: i64) -> bool {
+        self.is_superuser(user_id)
+            || self
+                .allowed_users
+                .as_ref()
+                .is_none_or(|allowed| allowed.contains(&user_id))
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct ChatConfig {
+    pub id: i64,
+    /// Watermark all the photos in the chat, not only the ones the bot is mentioned in
+    ///
+    /// Requires the bot to see all the messages, so either disable the privacy mode or make the bot an admin
+    #[serde(default)]
+    pub auto_watermark: bool,
+    /// Names of the presets to use in this chat. All the account presets are used if not set
+    #[serde(default)]
+    pub presets: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct ChannelConfig {
+    pub id: i64,
+    /// Name of the account that watches the channel, the first account if not set
+    #[serde(default)]
+    pub account: Option<String>,
+    /// Name of the preset to apply to the posts
+    pub preset: String,
+    pub mode: ChannelMode,
+}
+
 #[derive(Deserialize, Clone, Debug)]
+#[serde(tag = "type")]
+pub enum ChannelMode {
+    /// Replace the photo in the original post. Requires the account to be able to edit the posts
+    Edit,
+    /// Post the watermarked photo to another channel
+    Mirror {
+        /// Username of the channel to post to
+        target: String,
+    },
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct Storage {
+    /// Path to the SQLite database with the bot state. It is created if it doesn't exist
+    pub path: String,
+}
+
+impl Default for Storage {
+    fn default() -> Self {
+        Self {
+            path: "bot.sqlite".to_string(),
+        }
+    }
+}
+
+/// Shown instead of the credentials, as the resolved config is logged
+const REDACTED: &str = "<redacted>";
+
+#[derive(Deserialize, Clone)]
 pub struct Telegram {
     pub session_storage: Option<String>,
     pub api_id: i32,
     pub api_hash: String,
     pub account: TelegramAccount,
 }
-#[derive(Deserialize, Clone, Debug)]
+
+impl fmt::Debug for Telegram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Telegram")
+            .field("session_storage", &self.session_storage)
+            .field("api_id", &self.api_id)
+            .field("api_hash", &REDACTED)
+            .field("account", &self.account)
+            .finish()
+    }
+}
+
+#[derive(Deserialize, Clone)]
 #[serde(tag = "type")]
 pub enum TelegramAccount {
     PreparedSession {
@@ -62,16 +281,311 @@ pub enum TelegramAccount {
     },
     User {
         phone: String,
+        /// 2FA password, if the account has one. When not set, it is requested through `login_input`
+        #[serde(default)]
+        password: Option<String>,
+        #[serde(default)]
+        login_input: LoginInput,
+    },
+}
+
+impl fmt::Debug for TelegramAccount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TelegramAccount::PreparedSession { .. } => f
+                .debug_struct("PreparedSession")
+                .field("session", &REDACTED)
+                .finish(),
+            TelegramAccount::Bot { .. } => f.debug_struct("Bot").field("token", &REDACTED).finish(),
+            TelegramAccount::User {
+                phone,
+                password,
+                login_input,
+            } => f
+                .debug_struct("User")
+                .field("phone", phone)
+                .field("password", &password.as_ref().map(|_| REDACTED))
+                .field("login_input", login_input)
+                .finish(),
+        }
+    }
+}
+
+/// Where to get the login code (and the 2FA password) from when signing in as a user
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(tag = "type")]
+pub enum LoginInput {
+    /// Read them from stdin, one per line
+    #[default]
+    Stdin,
+    /// Poll the file system until the files appear, read them and remove them
+    ///
+    /// Useful for headless deployments, where one can `kubectl exec` into the pod and write the code to the file
+    File {
+        code_path: String,
+        password_path: String,
     },
 }
 
 #[derive(Deserialize, Clone, Debug)]
+#[serde(try_from = "RawMask")]
 pub struct Mask {
     pub presets: Vec<NamedPreset>,
 }
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize)]
+struct RawMask {
+    presets: Vec<presets::RawPreset>,
+}
+
+impl TryFrom<RawMask> for Mask {
+    type Error = anyhow::Error;
+
+    fn try_from(raw: RawMask) -> Result<Self> {
+        Ok(Self {
+            presets: presets::resolve_presets(raw.presets)?,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct NamedPreset {
     pub name: String,
     pub preset: crate::mask_generator::MaskConfig,
 }
+
+/// Parses a preset definition, written in YAML (or JSON) the same way as in the config file
+pub fn parse_preset(definition: &str) -> Result<crate::mask_generator::MaskConfig> {
+    let preset: crate::mask_generator::MaskConfig = config::Config::builder()
+        .add_source(config::File::from_str(definition, config::FileFormat::Yaml))
+        .build()
+        .context("Parsing the preset")?
+        .try_deserialize()
+        .context("Deserializing the preset")?;
+    preset.validate()?;
+    Ok(preset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_preset, Config, LoginInput, Mask, Telegram, TelegramAccount};
+    use indoc::indoc;
+    use serde::Deserialize;
+
+    #[test]
+    fn bundled_presets_load() {
+        #[derive(Deserialize)]
+        struct Masks {
+            masks: Mask,
+        }
+
+        let masks: Masks = config::Config::builder()
+            .add_source(config::File::from_str(
+                include_str!("../config.yaml"),
+                config::FileFormat::Yaml,
+            ))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap();
+
+        let names = masks
+            .masks
+            .presets
+            .iter()
+            .map(|p| p.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["ЧУПЛЫГИН УХОДИ #20", "ЧУПЛЫГИН УХОДИ #40"]);
+    }
+
+    fn resolve_config(channels: &str) -> anyhow::Result<Config> {
+        let yaml = format!(
+            indoc! {r##"
+                accounts:
+                  - name: "first"
+                    telegram: &telegram
+                      api_id: 1
+                      api_hash: "hash"
+                      account:
+                        type: "Bot"
+                        token: "token"
+                    presets: ["Red"]
+                  - name: "second"
+                    telegram: *telegram
+                masks:
+                  presets:
+                    - name: "Red"
+                      preset: &stripes
+                        alpha: 32
+                        content:
+                          type: "Stripes"
+                          color1: "#ff0000"
+                          color2: "#000000"
+                          stripe_count: 10
+                    - name: "Green"
+                      preset: *stripes
+                channels:
+                {}
+            "##},
+            channels
+        );
+        let config: Config = config::Config::builder()
+            .add_source(config::File::from_str(&yaml, config::FileFormat::Yaml))
+            .build()?
+            .try_deserialize()?;
+        config.resolve()
+    }
+
+    #[test]
+    fn channel_accounts() {
+        let config = resolve_config(indoc! {r#"
+              - id: 1
+                preset: "Red"
+                mode:
+                  type: "Edit"
+              - id: 2
+                account: "second"
+                preset: "Green"
+                mode:
+                  type: "Edit"
+        "#})
+        .unwrap();
+        // only one account watches a channel
+        let accounts = config
+            .channels
+            .iter()
+            .map(|c| c.account.as_deref())
+            .collect::<Vec<_>>();
+        assert_eq!(accounts, [Some("first"), Some("second")]);
+
+        // the first account doesn't offer the preset
+        assert!(resolve_config(indoc! {r#"
+              - id: 1
+                preset: "Green"
+                mode:
+                  type: "Edit"
+        "#})
+        .is_err());
+        assert!(resolve_config(indoc! {r#"
+              - id: 1
+                account: "third"
+                preset: "Red"
+                mode:
+                  type: "Edit"
+        "#})
+        .is_err());
+    }
+
+    #[test]
+    fn credentials_are_redacted() {
+        let telegram = |account| Telegram {
+            session_storage: None,
+            api_id: 1,
+            api_hash: "api-hash-secret".to_string(),
+            account,
+        };
+        let accounts = [
+            TelegramAccount::PreparedSession {
+                session: b"session-secret".to_vec(),
+            },
+            TelegramAccount::Bot {
+                token: "token-secret".to_string(),
+            },
+            TelegramAccount::User {
+                phone: "+10000000000".to_string(),
+                password: Some("password-secret".to_string()),
+                login_input: LoginInput::Stdin,
+            },
+        ];
+
+        // the session bytes would be printed as numbers
+        let session = format!("{:?}", b"session-secret");
+        let session = session.trim_matches(['[', ']']);
+        for account in accounts {
+            let debug = format!("{:?}", telegram(account));
+            assert!(!debug.contains("secret"), "{}", debug);
+            assert!(!debug.contains(session), "{}", debug);
+        }
+    }
+
+    #[test]
+    fn parse_preset_yaml() {
+        parse_preset(indoc! {r##"
+            alpha: 32
+            content:
+              type: "Stripes"
+              color1: "#ff0000"
+              color2: "#00ff00"
+              stripe_count: 10
+        "##})
+        .unwrap();
+
+        parse_preset(indoc! {r##"
+            alpha: 32
+            content:
+              type: "Text"
+              text: "ЧУПЛЫГИН\nУХОДИ"
+              font: "Comic Sans MS"
+              color:
+                light: "#ffffff"
+                dark: "#202020"
+              size: "5%short"
+              min_size: "12px"
+              max_size: 10
+              rotation: 45.0
+              row_slide: "1%width"
+              offset_x: "-30%diagonal"
+              stride_x: "300px"
+              offset_y: -20.0
+              stride_y: "20%height"
+              font_weight: 700
+              font_style: "Italic"
+              letter_spacing: "2px"
+              line_height: 1.5
+              align: "Center"
+              stroke:
+                color: "#000000"
+                width: 0.3
+              shadow:
+                color: "#000000"
+                offset_x: "2px"
+                offset_y: 0.2
+                blur: "0.3%short"
+              background:
+                color: "#000000"
+                padding: 1.0
+                corner_radius: "8px"
+              jitter:
+                offset: "1%short"
+                rotation: 10.0
+                scale: 0.1
+                opacity: 0.3
+        "##})
+        .unwrap();
+
+        assert!(parse_preset("alpha: 32").is_err());
+
+        let error = parse_preset(indoc! {r##"
+            alpha: 32
+            content:
+              type: "Text"
+              text: "ЧУПЛЫГИН УХОДИ"
+              font: "Comic Sans MS"
+              color: "#ffffff"
+              size: 5
+              rotation: 45.0
+              row_slide: 1
+              offset_x: -30
+              stride_x: 30
+              offset_y: -20
+              stride_y: 20
+              shadow:
+                color: "#000000"
+                offset_x_percent: 0.2
+                offset_y_percent: 0.2
+                blur_percent: -0.3
+        "##})
+        .unwrap_err();
+        assert_eq!(error.to_string(), "`shadow.blur` can't be negative");
+    }
+}
@@ -0,0 +1,464 @@
+//! Resolution of the preset inheritance and matrices, done when loading the config
+
+use super::NamedPreset;
+use crate::mask_generator::MaskConfig;
+use anyhow::{bail, Context as _, Result};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// A preset as written in the config, before the inheritance and the matrix are resolved
+#[derive(Deserialize, Clone, Debug)]
+pub struct RawPreset {
+    /// Can refer to the matrix values, like `Preset {alpha}`
+    pub name: String,
+    /// Name of the preset to take the fields from. Only the fields that differ have to be set then
+    #[serde(default)]
+    pub extends: Option<String>,
+    /// Only used as a base for the other presets, not offered to the users
+    #[serde(default)]
+    pub hidden: bool,
+    /// Expands the preset into one preset per combination of the values
+    ///
+    /// Keys are dotted paths to the preset fields, like `content.size`.
+    /// A list of values set right in the preset, like `alpha: [32, 64]`, is a shorthand for the same
+    #[serde(default)]
+    pub matrix: BTreeMap<String, Vec<Value>>,
+    #[serde(default)]
+    pub preset: Value,
+}
+
+/// Fields renamed when the sizes got units, as dotted paths of the old and the new name
+///
+/// The old names are still accepted, but they have to be renamed before merging the presets,
+/// or a preset that sets `size` extending one that sets `size_percent` would end up with both
+const LEGACY_FIELDS: [(&str, &str); 13] = [
+    ("content.size_percent", "content.size"),
+    ("content.letter_spacing_percent", "content.letter_spacing"),
+    ("content.row_slide_percent", "content.row_slide"),
+    ("content.offset_x_percent", "content.offset_x"),
+    ("content.stride_x_percent", "content.stride_x"),
+    ("content.offset_y_percent", "content.offset_y"),
+    ("content.stride_y_percent", "content.stride_y"),
+    ("content.stroke.width_percent", "content.stroke.width"),
+    ("content.shadow.offset_x_percent", "content.shadow.offset_x"),
+    ("content.shadow.offset_y_percent", "content.shadow.offset_y"),
+    ("content.shadow.blur_percent", "content.shadow.blur"),
+    (
+        "content.background.padding_percent",
+        "content.background.padding",
+    ),
+    (
+        "content.background.corner_radius_percent",
+        "content.background.corner_radius",
+    ),
+];
+
+impl RawPreset {
+    /// Renames the legacy fields, both in the preset and in the matrix
+    ///
+    /// The name is kept, as other presets may extend it, and its placeholders of the old names are still filled in
+    fn rename_legacy_fields(mut self) -> Result<Self> {
+        for (old, new) in LEGACY_FIELDS {
+            if let Some(value) = take_path(&mut self.preset, old) {
+                if get_path(&self.preset, new).is_some() {
+                    bail!(
+                        "Preset `{}` sets both `{}` and `{}`, which is its older name",
+                        self.name,
+                        new,
+                        old
+                    );
+                }
+                set_path(&mut self.preset, new, value);
+            }
+            if let Some(values) = self.matrix.remove(old) {
+                if self.matrix.contains_key(new) {
+                    bail!(
+                        "Matrix of preset `{}` has both `{}` and `{}`, which is its older name",
+                        self.name,
+                        new,
+                        old
+                    );
+                }
+                self.matrix.insert(new.to_string(), values);
+            }
+        }
+        Ok(self)
+    }
+}
+
+pub fn resolve_presets(raw: Vec<RawPreset>) -> Result<Vec<NamedPreset>> {
+    let raw = raw
+        .into_iter()
+        .map(RawPreset::rename_legacy_fields)
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut by_name = HashMap::new();
+    for preset in &raw {
+        if by_name.insert(preset.name.as_str(), preset).is_some() {
+            bail!("Duplicate preset name `{}`", preset.name);
+        }
+    }
+
+    let mut presets = Vec::new();
+    let mut names = HashSet::new();
+    for preset in raw.iter().filter(|p| !p.hidden) {
+        let value = resolve_inheritance(preset, &by_name, &mut Vec::new())?;
+        let mut matrix = preset.matrix.clone();
+        inline_matrix(&preset.name, &value, "", &mut matrix)?;
+
+        for (name, value) in expand_matrix(&preset.name, &matrix, value)? {
+            let preset: MaskConfig = serde_json::from_value(value)
+                .with_context(|| format!("Invalid preset `{}`", name))?;
+            preset
+                .validate()
+                .with_context(|| format!("Invalid preset `{}`", name))?;
+            if !names.insert(name.clone()) {
+                bail!(
+                    "Duplicate preset name `{}` after expanding the matrix",
+                    name
+                );
+            }
+            presets.push(NamedPreset { name, preset });
+        }
+    }
+
+    Ok(presets)
+}
+
+/// Returns the preset fields with the fields of the base presets filled in
+fn resolve_inheritance<'a>(
+    preset: &'a RawPreset,
+    by_name: &HashMap<&str, &'a RawPreset>,
+    chain: &mut Vec<&'a str>,
+) -> Result<Value> {
+    if chain.contains(&preset.name.as_str()) {
+        chain.push(&preset.name);
+        bail!("Preset inheritance cycle: {}", chain.join(" -> "));
+    }
+    chain.push(&preset.name);
+
+    let Some(base) = &preset.extends else {
+        return Ok(preset.preset.clone());
+    };
+    let base = by_name.get(base.as_str()).with_context(|| {
+        format!(
+            "Preset `{}` extends an unknown preset `{}`",
+            preset.name, base
+        )
+    })?;
+
+    let mut value = resolve_inheritance(base, by_name, chain)?;
+    merge(&mut value, &preset.preset);
+    Ok(value)
+}
+
+/// Overrides the fields of `base` with the ones set in `overrides`, recursing into the nested objects
+fn merge(base: &mut Value, overrides: &Value) {
+    match (base, overrides) {
+        (Value::Object(base), Value::Object(overrides)) => {
+            for (key, value) in overrides {
+                match base.get_mut(key) {
+                    Some(base_value) => merge(base_value, value),
+                    None => {
+                        base.insert(key.clone(), value.clone());
+                    }
+                }
+            }
+        }
+        (base, overrides) => *base = overrides.clone(),
+    }
+}
+
+/// Adds the lists of values set right in the preset fields to the matrix
+///
+/// None of the preset fields is a list itself, so a list can only mean the values to expand
+fn inline_matrix(
+    name: &str,
+    value: &Value,
+    path: &str,
+    matrix: &mut BTreeMap<String, Vec<Value>>,
+) -> Result<()> {
+    match value {
+        Value::Object(fields) => {
+            for (key, value) in fields {
+                let path = match path {
+                    "" => key.clone(),
+                    path => format!("{}.{}", path, key),
+                };
+                inline_matrix(name, value, &path, matrix)?;
+            }
+        }
+        Value::Array(_) if matrix.contains_key(path) => bail!(
+            "Preset `{}` sets `{}` both to a list of values and in the matrix",
+            name,
+            path
+        ),
+        Value::Array(values) => {
+            matrix.insert(path.to_string(), values.clone());
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn expand_matrix(
+    name_template: &str,
+    matrix: &BTreeMap<String, Vec<Value>>,
+    value: Value,
+) -> Result<Vec<(String, Value)>> {
+    let mut expanded = vec![(name_template.to_string(), value)];
+
+    for (path, values) in matrix {
+        if values.is_empty() {
+            bail!(
+                "Matrix of preset `{}` has no values for `{}`",
+                name_template,
+                path
+            );
+        }
+
+        expanded = expanded
+            .into_iter()
+            .flat_map(|(name, value)| {
+                values.iter().map(move |field_value| {
+                    let mut value = value.clone();
+                    set_path(&mut value, path, field_value.clone());
+                    let name = placeholders(path).fold(name.clone(), |name, placeholder| {
+                        name.replace(&placeholder, &display_value(field_value))
+                    });
+                    (name, value)
+                })
+            })
+            .collect();
+    }
+
+    Ok(expanded)
+}
+
+/// Placeholders of the field in the preset names, including the one of its legacy name
+fn placeholders(path: &str) -> impl Iterator<Item = String> + '_ {
+    let legacy = LEGACY_FIELDS
+        .iter()
+        .filter(move |(_, new)| *new == path)
+        .map(|(old, _)| *old);
+    std::iter::once(path)
+        .chain(legacy)
+        .map(|path| format!("{{{}}}", path))
+}
+
+fn set_path(value: &mut Value, path: &str, field_value: Value) {
+    let mut current = value;
+    for key in path.split('.') {
+        if !current.is_object() {
+            *current = Value::Object(Map::new());
+        }
+        current = current
+            .as_object_mut()
+            .unwrap()
+            .entry(key)
+            .or_insert(Value::Null);
+    }
+    *current = field_value;
+}
+
+fn get_path<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.')
+        .try_fold(value, |current, key| current.get(key))
+}
+
+/// Removes the field at the path, returning its value
+fn take_path(value: &mut Value, path: &str) -> Option<Value> {
+    let (parent, key) = match path.rsplit_once('.') {
+        Some((parent, key)) => (
+            parent
+                .split('.')
+                .try_fold(value, |current, key| current.get_mut(key))?,
+            key,
+        ),
+        None => (value, path),
+    };
+    parent.as_object_mut()?.remove(key)
+}
+
+/// Formats the value for the preset name, without the quotes around the strings
+fn display_value(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        value => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{resolve_presets, RawPreset};
+    use serde_json::json;
+
+    fn raw(value: serde_json::Value) -> Vec<RawPreset> {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn base() -> serde_json::Value {
+        json!({
+            "alpha": 32,
+            "content": {
+                "type": "Stripes",
+                "color1": "#ff0000",
+                "color2": "#00ff00",
+                "stripe_count": 10
+            }
+        })
+    }
+
+    #[test]
+    fn inheritance() {
+        let presets = resolve_presets(raw(json!([
+            { "name": "base", "hidden": true, "preset": base() },
+            { "name": "child", "extends": "base", "preset": { "content": { "stripe_count": 4 } } },
+            { "name": "grandchild", "extends": "child", "preset": { "alpha": 64 } },
+        ])))
+        .unwrap();
+
+        let names = presets.iter().map(|p| p.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["child", "grandchild"]);
+        assert_eq!(
+            format!("{:?}", presets[1].preset),
+            format!("{:?}", {
+                let mut expected = base();
+                expected["alpha"] = json!(64);
+                expected["content"]["stripe_count"] = json!(4);
+                serde_json::from_value::<crate::mask_generator::MaskConfig>(expected).unwrap()
+            })
+        );
+    }
+
+    #[test]
+    fn legacy_fields() {
+        let text = json!({
+            "alpha": 32,
+            "content": {
+                "type": "Text",
+                "text": "ЧУПЛЫГИН УХОДИ",
+                "font": "Comic Sans MS",
+                "color": "#ffffff",
+                "size_percent": 5.0,
+                "rotation": 45.0,
+                "row_slide_percent": 1.0,
+                "offset_x_percent": -30.0,
+                "stride_x_percent": 30.0,
+                "offset_y_percent": -20.0,
+                "stride_y_percent": 20.0,
+                "shadow": {
+                    "color": "#000000",
+                    "offset_x_percent": 0.2,
+                    "offset_y_percent": 0.2,
+                    "blur_percent": 0.3
+                }
+            }
+        });
+        let presets = resolve_presets(raw(json!([
+            { "name": "base", "hidden": true, "preset": text },
+            {
+                "name": "child {content.stride_x_percent}",
+                "extends": "base",
+                "matrix": { "content.stride_x_percent": [20, "300px"] },
+                "preset": { "content": { "size": "40px", "shadow": { "blur": "2px" } } }
+            },
+        ])))
+        .unwrap();
+
+        let names = presets.iter().map(|p| p.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["child 20", "child 300px"]);
+
+        let error = resolve_presets(raw(json!([
+            { "name": "both", "preset": { "content": { "size": 5, "size_percent": 5 } } },
+        ])))
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Preset `both` sets both `content.size` and `content.size_percent`, which is its older name"
+        );
+    }
+
+    #[test]
+    fn inheritance_cycle() {
+        let error = resolve_presets(raw(json!([
+            { "name": "a", "extends": "b", "preset": {} },
+            { "name": "b", "extends": "a", "preset": {} },
+        ])))
+        .unwrap_err();
+
+        assert_eq!(error.to_string(), "Preset inheritance cycle: a -> b -> a");
+    }
+
+    #[test]
+    fn matrix() {
+        let presets = resolve_presets(raw(json!([
+            {
+                "name": "stripes {content.stripe_count} @{alpha}",
+                "matrix": { "alpha": [32, 64], "content.stripe_count": [2, 4] },
+                "preset": base()
+            },
+        ])))
+        .unwrap();
+
+        let names = presets.iter().map(|p| p.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                "stripes 2 @32",
+                "stripes 4 @32",
+                "stripes 2 @64",
+                "stripes 4 @64"
+            ]
+        );
+    }
+
+    #[test]
+    fn inline_matrix() {
+        let presets = resolve_presets(raw(json!([
+            {
+                "name": "base",
+                "hidden": true,
+                "preset": { "alpha": [32, 64], "content": base()["content"] }
+            },
+            {
+                "name": "stripes {content.stripe_count} @{alpha}",
+                "extends": "base",
+                "matrix": { "content.stripe_count": [2, 4] },
+                "preset": {}
+            },
+        ])))
+        .unwrap();
+
+        let names = presets.iter().map(|p| p.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                "stripes 2 @32",
+                "stripes 4 @32",
+                "stripes 2 @64",
+                "stripes 4 @64"
+            ]
+        );
+
+        let error = resolve_presets(raw(json!([
+            { "name": "both", "matrix": { "alpha": [32] }, "preset": { "alpha": [64] } },
+        ])))
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Preset `both` sets `alpha` both to a list of values and in the matrix"
+        );
+    }
+
+    #[test]
+    fn matrix_without_name_placeholders() {
+        let error = resolve_presets(raw(json!([
+            { "name": "same", "matrix": { "alpha": [32, 64] }, "preset": base() },
+        ])))
+        .unwrap_err();
+
+        assert!(error.to_string().contains("Duplicate preset name"));
+    }
+}
@@ -6,6 +6,8 @@ use grammers_session::Session;
 use indoc::indoc;
 use tracing::{debug, info, warn};
 
+use crate::config::LoginInput;
+
 pub async fn connect_and_login(config: &crate::config::Telegram) -> Result<Client> {
     let mut catch_up = false;
 
@@ -64,7 +66,7 @@ pub async fn connect_and_login(config: &crate::config::Telegram) -> Result<Clien
                     "{}",
                     indoc!(
                         r#"Prepared session is not signed in, please sign in manually
-                        and provide the session file"#
+                        and provide the session file (the `login` subcommand can prepare one)"#
                     )
                 ));
             }
@@ -75,43 +77,12 @@ pub async fn connect_and_login(config: &crate::config::Telegram) -> Result<Clien
                     .await
                     .context("Signing in as bot")?;
             }
-            crate::config::TelegramAccount::User { phone } => {
-                info!("Signing in as user");
-                let login_token = client
-                    .request_login_code(phone)
-                    .await
-                    .context("Requesting login code")?;
-
-                info!("Asked telegram for login code, waiting for it to be entered");
-
-                let mut logic_code = String::new();
-                std::io::stdin()
-                    .read_line(&mut logic_code)
-                    .context("Reading login code")?;
-                let logic_code = logic_code.strip_suffix('\n').unwrap();
-
-                match client.sign_in(&login_token, logic_code).await {
-                    Ok(_) => {}
-                    Err(SignInError::PasswordRequired(password_token)) => {
-                        info!(
-                            "2FA Password required, asking for it. Password hint: {}",
-                            password_token.hint().unwrap()
-                        );
-                        let mut password = String::new();
-                        std::io::stdin()
-                            .read_line(&mut password)
-                            .context("Reading password")?;
-                        let password = password.strip_suffix('\n').unwrap();
-
-                        client
-                            .check_password(password_token, password)
-                            .await
-                            .context("Checking password")?;
-                    }
-                    Err(e) => {
-                        return Err(e).context("Signing in as user");
-                    }
-                }
+            crate::config::TelegramAccount::User {
+                phone,
+                password,
+                login_input,
+            } => {
+                sign_in_user(&client, phone, password.as_deref(), login_input).await?;
             }
         }
 
@@ -126,6 +97,144 @@ pub async fn connect_and_login(config: &crate::config::Telegram) -> Result<Clien
     Ok(client)
 }
 
+async fn sign_in_user(
+    client: &Client,
+    phone: &str,
+    password: Option<&str>,
+    login_input: &LoginInput,
+) -> Result<()> {
+    info!("Signing in as user");
+    let login_token = client
+        .request_login_code(phone)
+        .await
+        .context("Requesting login code")?;
+
+    info!("Asked telegram for login code, waiting for it to be entered");
+
+    let login_code = read_login_input(login_input, LoginInputKind::Code).await?;
+
+    match client.sign_in(&login_token, &login_code).await {
+        Ok(_) => {}
+        Err(SignInError::PasswordRequired(password_token)) => {
+            let password = match password {
+                Some(password) => {
+                    info!("2FA Password required, using the one from the config");
+                    password.to_string()
+                }
+                None => {
+                    info!(
+                        "2FA Password required, asking for it. Password hint: {}",
+                        password_token.hint().unwrap_or("<none>")
+                    );
+                    read_login_input(login_input, LoginInputKind::Password).await?
+                }
+            };
+
+            client
+                .check_password(password_token, password)
+                .await
+                .context("Checking password")?;
+        }
+        Err(e) => {
+            return Err(e).context("Signing in as user");
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Copy, Clone)]
+enum LoginInputKind {
+    Code,
+    Password,
+}
+
+async fn read_login_input(login_input: &LoginInput, kind: LoginInputKind) -> Result<String> {
+    match login_input {
+        LoginInput::Stdin => {
+            let line = tokio::task::spawn_blocking(move || {
+                let mut line = String::new();
+                std::io::stdin()
+                    .read_line(&mut line)
+                    .with_context(|| format!("Reading {:?} from stdin", kind))?;
+                Ok::<_, anyhow::Error>(line)
+            })
+            .await??;
+
+            Ok(line.trim_end_matches(['\r', '\n']).to_string())
+        }
+        LoginInput::File {
+            code_path,
+            password_path,
+        } => {
+            let path = Path::new(match kind {
+                LoginInputKind::Code => code_path,
+                LoginInputKind::Password => password_path,
+            });
+            info!("Waiting for {:?} to be written to {}", kind, path.display());
+
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(2));
+            loop {
+                interval.tick().await;
+
+                let contents = match std::fs::read_to_string(path) {
+                    Ok(contents) => contents,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                    Err(e) => return Err(e).with_context(|| format!("Reading {}", path.display())),
+                };
+                let contents = contents.trim_end_matches(['\r', '\n']);
+                // the file might have been created, but not written to yet
+                if contents.is_empty() {
+                    continue;
+                }
+
+                // remove the file so that a stale value is not picked up on the next login
+                std::fs::remove_file(path)
+                    .with_context(|| format!("Removing {}", path.display()))?;
+
+                return Ok(contents.to_string());
+            }
+        }
+    }
+}
+
+/// Signs in as a user into a fresh session and returns the session data
+///
+/// The result is meant to be used with [`crate::config::TelegramAccount::PreparedSession`]
+pub async fn login_prepared_session(
+    config: &crate::config::Telegram,
+    phone: Option<String>,
+) -> Result<Vec<u8>> {
+    let (config_phone, password, login_input) = match &config.account {
+        crate::config::TelegramAccount::User {
+            phone,
+            password,
+            login_input,
+        } => (
+            Some(phone.as_str()),
+            password.as_deref(),
+            login_input.clone(),
+        ),
+        _ => (None, None, LoginInput::Stdin),
+    };
+    let phone = phone.as_deref().or(config_phone).context(
+        "No phone number given, either pass it as an argument or configure a user account",
+    )?;
+
+    let client = Client::connect(Config {
+        session: Session::new(),
+        api_id: config.api_id,
+        api_hash: config.api_hash.clone(),
+        params: Default::default(),
+    })
+    .await
+    .context("Connecting to telegram")?;
+
+    sign_in_user(&client, phone, password, &login_input).await?;
+
+    Ok(client.session().save())
+}
+
 pub fn save_session(client: &Client, config: &crate::config::Telegram) -> Result<()> {
     if let Some(session_storage) = &config.session_storage {
         debug!("Saving session to {}", session_storage);
@@ -0,0 +1,4 @@
+//! The image processing part of the bot, kept in a library so that it can be benchmarked
+
+pub mod cancellation;
+pub mod mask_generator;
@@ -1,11 +1,14 @@
-use anyhow::{Context as _, Result};
-use tracing::{error, info};
+use anyhow::{bail, Context as _, Result};
+use serezha_watermark_bot::{cancellation, mask_generator};
+use std::sync::Arc;
+use tokio::task::JoinSet;
+use tracing::{error, info, instrument};
 
 mod bot;
 mod config;
 mod grammers_boilerplate;
 mod init_tracing;
-mod mask_generator;
+mod storage;
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<()> {
@@ -19,19 +22,104 @@ async fn main() -> Result<()> {
 
     info!("Resolved config: {:#?}", config);
 
-    let client = grammers_boilerplate::connect_and_login(&config.telegram).await?;
+    let mut args = std::env::args().skip(1);
+    match args.next().as_deref() {
+        None => run(config).await,
+        Some("login") => login(config, args.next()).await,
+        Some(other) => {
+            bail!("Unknown subcommand `{other}`, expected either nothing or `login [phone]`")
+        }
+    }
+}
+
+/// Signs in as a user and prints the session to be used as a `PreparedSession` account
+async fn login(config: config::Config, phone: Option<String>) -> Result<()> {
+    // prefer a user account, as it has the phone number and the login input configured
+    let account = config
+        .accounts
+        .iter()
+        .find(|a| matches!(a.telegram.account, config::TelegramAccount::User { .. }))
+        .unwrap_or(&config.accounts[0]);
+
+    let session = grammers_boilerplate::login_prepared_session(&account.telegram, phone).await?;
+
+    let session = session
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+
+    info!("Signed in successfully, printing the prepared session to stdout");
+    println!("{}", session);
+
+    Ok(())
+}
+
+async fn run(config: config::Config) -> Result<()> {
+    // the renderer and the storage are shared between all the accounts
+    let renderer = Arc::new(mask_generator::MaskRenderer::new());
+    let storage = Arc::new(storage::Storage::open(&config.storage.path)?);
+
+    let (shutdown_sender, shutdown) = tokio::sync::watch::channel(false);
+
+    let mut accounts = JoinSet::new();
+    for account in &config.accounts {
+        let context = Arc::new(bot::BotContext::new(
+            &config,
+            account,
+            renderer.clone(),
+            storage.clone(),
+        ));
+
+        accounts.spawn(run_account(account.clone(), context, shutdown.clone()));
+    }
 
     tokio::select!(
         _ = tokio::signal::ctrl_c() => {
             info!("Got SIGINT; quitting early gracefully");
         }
-        r = bot::run_bot(&client, config.masks.clone()) => {
+        _ = wait_for_accounts(&mut accounts) => {
+            info!("All the accounts have stopped");
+        }
+    );
+
+    // let the accounts save their sessions
+    shutdown_sender.send_replace(true);
+    wait_for_accounts(&mut accounts).await;
+
+    Ok(())
+}
+
+async fn wait_for_accounts(accounts: &mut JoinSet<()>) {
+    while let Some(r) = accounts.join_next().await {
+        if let Err(e) = r {
+            error!("Account task panicked: {}", e);
+        }
+    }
+}
+
+#[instrument(skip_all, fields(account = account.name))]
+async fn run_account(
+    account: config::Account,
+    context: Arc<bot::BotContext>,
+    mut shutdown: tokio::sync::watch::Receiver<bool>,
+) {
+    let client = match grammers_boilerplate::connect_and_login(&account.telegram).await {
+        Ok(client) => client,
+        Err(e) => {
+            error!("Connecting to telegram failed: {:?}", e);
+            return;
+        }
+    };
+
+    tokio::select!(
+        _ = shutdown.wait_for(|&shutdown| shutdown) => {}
+        r = bot::run_bot(&client, context) => {
             match r {
                 Ok(_) => info!("Got disconnected from Telegram gracefully"),
                 Err(e) => error!("Error during update handling: {}", e),
             }
         }
-        r = grammers_boilerplate::save_session_periodic(&client, &config.telegram) => {
+        r = grammers_boilerplate::save_session_periodic(&client, &account.telegram) => {
             match r {
                 Ok(_) => unreachable!(),
                 Err(e) => error!("Error during session saving: {}", e),
@@ -39,7 +127,7 @@ async fn main() -> Result<()> {
         }
     );
 
-    grammers_boilerplate::save_session(&client, &config.telegram)?;
-
-    Ok(())
+    if let Err(e) = grammers_boilerplate::save_session(&client, &account.telegram) {
+        error!("Error during session saving: {:?}", e);
+    }
 }
@@ -1,10 +1,39 @@
-use image::{GenericImage, Pixel, Primitive, Rgb, Rgba};
+use crate::cancellation::{CancellationToken, Cancelled};
+use image::{GenericImage, ImageBuffer, Pixel, Primitive, Rgb, Rgba};
 use num_traits::cast::ToPrimitive;
 use num_traits::NumCast;
 use serde::Deserialize;
-use tiny_skia::IntSize;
+use std::sync::Arc;
+use tiny_skia::{
+    FillRule, IntSize, LineJoin, Paint, Path, PathBuilder, PathSegment, PixmapPaint, Rect,
+    Transform,
+};
 use xml_builder::{XMLBuilder, XMLElement, XMLVersion};
 
+use color_space::{linear_to_srgb, srgb_to_linear};
+use jitter::{Instance, Jitter, Variant};
+use luminance::Luminance;
+use sprite::{Sprite, Stamp};
+use text::TextStyle;
+
+mod blend;
+mod blur;
+mod color_space;
+mod fast;
+mod jitter;
+mod length;
+mod luminance;
+mod sprite;
+mod text;
+
+pub use blend::BlendMode;
+pub use color_space::ColorSpace;
+pub use fast::{composite_mask_fast, FastPixel};
+pub use length::{Length, LengthUnit};
+pub use text::{FontStyle, TextAlign};
+
+// there are only a few of the configs around, so the size of the text one doesn't matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Clone, Debug, Deserialize)]
 #[serde(tag = "type")]
 pub enum MaskContent {
@@ -14,25 +43,257 @@ pub enum MaskContent {
         stripe_count: u32,
     },
     Text {
+        /// Can span multiple lines, separated with `\n`
         text: String,
         font: String,
-        color: Color,
-        size_percent: f32,
+        /// Like in CSS, 400 is regular and 700 is bold
+        ///
+        /// Without a face of the weight the glyphs are thickened, and without an italic face they are slanted
+        #[serde(default = "default_font_weight")]
+        font_weight: u16,
+        #[serde(default)]
+        font_style: FontStyle,
+        color: TextColor,
+        #[serde(alias = "size_percent")]
+        size: Length,
+        /// Keeps the text legible on small images
+        #[serde(default)]
+        min_size: Option<Length>,
+        #[serde(default)]
+        max_size: Option<Length>,
+        #[serde(default, alias = "letter_spacing_percent")]
+        letter_spacing: Option<Length>,
+        /// Distance between the lines, relative to the font size
+        #[serde(default = "default_line_height")]
+        line_height: f32,
+        #[serde(default)]
+        align: TextAlign,
         rotation: f32,
-        row_slide_percent: f32,
-        offset_x_percent: f32,
-        stride_x_percent: f32,
-        offset_y_percent: f32,
-        stride_y_percent: f32,
+        #[serde(alias = "row_slide_percent")]
+        row_slide: Length,
+        #[serde(alias = "offset_x_percent")]
+        offset_x: Length,
+        #[serde(alias = "stride_x_percent")]
+        stride_x: Length,
+        #[serde(alias = "offset_y_percent")]
+        offset_y: Length,
+        #[serde(alias = "stride_y_percent")]
+        stride_y: Length,
+        stroke: Option<TextStroke>,
+        shadow: Option<TextShadow>,
+        background: Option<TextBackground>,
+        jitter: Option<TextJitter>,
     },
 }
 
+fn default_font_weight() -> u16 {
+    400
+}
+
+fn default_line_height() -> f32 {
+    1.2
+}
+
+/// Color of the text glyphs
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum TextColor {
+    Fixed(Color),
+    /// For each copy of the text, the one of the two colors that stands out more on the image under it
+    Auto {
+        light: Color,
+        dark: Color,
+    },
+}
+
+impl TextColor {
+    /// The color used when the image is not known
+    fn default_color(&self) -> &Color {
+        match self {
+            TextColor::Fixed(color) => color,
+            TextColor::Auto { light, .. } => light,
+        }
+    }
+}
+
+/// Outline drawn around the glyphs
+#[derive(Clone, Debug, Deserialize)]
+pub struct TextStroke {
+    color: Color,
+    #[serde(alias = "width_percent")]
+    width: Length,
+}
+
+/// Blurred copy of the text drawn under it
+#[derive(Clone, Debug, Deserialize)]
+pub struct TextShadow {
+    color: Color,
+    #[serde(alias = "offset_x_percent")]
+    offset_x: Length,
+    #[serde(alias = "offset_y_percent")]
+    offset_y: Length,
+    /// Standard deviation of the blur
+    #[serde(alias = "blur_percent")]
+    blur: Length,
+}
+
+/// Rounded box drawn behind each copy of the text
+#[derive(Clone, Debug, Deserialize)]
+pub struct TextBackground {
+    color: Color,
+    #[serde(alias = "padding_percent")]
+    padding: Length,
+    #[serde(alias = "corner_radius_percent")]
+    corner_radius: Length,
+}
+
+/// Random changes of each copy of the text, so that the grid can't be removed by periodic filtering
+///
+/// The changes are derived from the seed, so the same seed always gives the same mask
+#[derive(Clone, Debug, Deserialize)]
+pub struct TextJitter {
+    /// When not set, a value of the request is used, like the ID of the user the image is made for
+    ///
+    /// The renders not made for a request, like the exported SVGs and the benchmarks, use 0 then
+    #[serde(default)]
+    seed: Option<u64>,
+    /// The most a copy is moved by, in each direction
+    #[serde(default)]
+    offset: Option<Length>,
+    /// The most a copy is rotated by, in degrees
+    #[serde(default)]
+    rotation: f32,
+    /// The most the size of a copy is changed by, like `0.1` for 90% to 110%. Must be less than 1
+    #[serde(default)]
+    scale: f32,
+    /// The most the opacity of a copy is reduced by, like `0.3` for 70% to 100%
+    #[serde(default)]
+    opacity: f32,
+}
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct MaskConfig {
     alpha: u8,
+    /// How the mask colors are combined with the image colors
+    #[serde(default)]
+    blend_mode: BlendMode,
+    #[serde(default)]
+    color_space: ColorSpace,
     content: MaskContent,
 }
 
+impl MaskConfig {
+    /// Whether the mask depends on the image it's applied to
+    fn needs_image(&self) -> bool {
+        matches!(
+            self.content,
+            MaskContent::Text {
+                color: TextColor::Auto { .. },
+                ..
+            }
+        )
+    }
+
+    /// Checks the values that deserialize fine but can't be rendered
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let MaskContent::Text {
+            size,
+            min_size,
+            max_size,
+            line_height,
+            stride_x,
+            stride_y,
+            stroke,
+            shadow,
+            background,
+            jitter,
+            ..
+        } = &self.content
+        else {
+            return Ok(());
+        };
+
+        let positive = [
+            Some(("size", size)),
+            min_size.as_ref().map(|s| ("min_size", s)),
+            max_size.as_ref().map(|s| ("max_size", s)),
+            Some(("stride_x", stride_x)),
+            Some(("stride_y", stride_y)),
+        ];
+        for (name, length) in positive.into_iter().flatten() {
+            if length.value <= 0.0 {
+                anyhow::bail!("`{}` must be positive", name);
+            }
+        }
+        if *line_height <= 0.0 {
+            anyhow::bail!("`line_height` must be positive");
+        }
+        // the sizes in different units can only be compared for a particular image
+        if let (Some(min_size), Some(max_size)) = (min_size, max_size) {
+            if min_size.unit == max_size.unit && min_size.value > max_size.value {
+                anyhow::bail!("`min_size` can't be larger than `max_size`");
+            }
+        }
+
+        let sizes = [
+            stroke.as_ref().map(|s| ("stroke.width", &s.width)),
+            shadow.as_ref().map(|s| ("shadow.blur", &s.blur)),
+            background
+                .as_ref()
+                .map(|b| ("background.padding", &b.padding)),
+            background
+                .as_ref()
+                .map(|b| ("background.corner_radius", &b.corner_radius)),
+            jitter
+                .as_ref()
+                .and_then(|j| Some(("jitter.offset", j.offset.as_ref()?))),
+        ];
+        for (name, size) in sizes.into_iter().flatten() {
+            if size.value < 0.0 {
+                anyhow::bail!("`{}` can't be negative", name);
+            }
+        }
+
+        if let Some(jitter) = jitter {
+            if jitter.rotation < 0.0 {
+                anyhow::bail!("`jitter.rotation` can't be negative");
+            }
+            // a copy scaled by `1 - scale` would vanish at 1 and be mirrored past it
+            if !(0.0..1.0).contains(&jitter.scale) {
+                anyhow::bail!("`jitter.scale` must be at least 0 and less than 1");
+            }
+            if !(0.0..=1.0).contains(&jitter.opacity) {
+                anyhow::bail!("`jitter.opacity` must be between 0 and 1");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the same preset with a different text, or `None` if the preset is not a text one
+    pub fn with_text(&self, new_text: &str) -> Option<MaskConfig> {
+        let mut mask = self.clone();
+        match &mut mask.content {
+            MaskContent::Text { text, .. } => *text = new_text.to_string(),
+            MaskContent::Stripes { .. } => return None,
+        }
+        Some(mask)
+    }
+
+    /// Returns the same preset with the jitter seeded with a value of the request, unless the preset has its own seed
+    pub fn with_request_seed(&self, request_seed: u64) -> MaskConfig {
+        let mut mask = self.clone();
+        if let MaskContent::Text {
+            jitter: Some(jitter),
+            ..
+        } = &mut mask.content
+        {
+            jitter.seed.get_or_insert(request_seed);
+        }
+        mask
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Color {
     rgb: [u8; 3],
@@ -43,6 +304,18 @@ impl Color {
         let [r, g, b] = self.rgb;
         format!("#{:02x}{:02x}{:02x}{:02x}", r, g, b, alpha)
     }
+
+    /// Luma of the color, in `0.0..=1.0`
+    fn luma(&self) -> f32 {
+        luminance::luma(self.rgb.map(|c| c as f32 / 255.0))
+    }
+
+    fn paint(&self, alpha: u8) -> Paint<'static> {
+        let [r, g, b] = self.rgb;
+        let mut paint = Paint::default();
+        paint.set_color_rgba8(r, g, b, alpha);
+        paint
+    }
 }
 
 impl<'de> Deserialize<'de> for Color {
@@ -102,122 +375,674 @@ impl Iterator for CoordinateIter {
     }
 }
 
-fn generate_mask_svg(mask: MaskConfig, mask_width: u32, mask_height: u32) -> String {
-    let mut xml = XMLBuilder::new()
-        .version(XMLVersion::XML1_1)
-        .encoding("UTF-8".into())
-        .build();
-
-    let mut svg = XMLElement::new("svg");
-    svg.add_attribute("xmlns", "http://www.w3.org/2000/svg");
-    svg.add_attribute("viewbox", &format!("0 0 {mask_width} {mask_height}"));
-
-    let MaskConfig {
-        alpha,
-        content: mask_content,
-    } = mask;
-
-    match mask_content {
-        MaskContent::Stripes {
-            color1,
-            color2,
-            stripe_count,
-        } => {
-            let color1 = color1.hex_with_alpha(alpha);
-            let color2 = color2.hex_with_alpha(alpha);
-
-            for i in 0..stripe_count {
-                let y = mask_height * i / stripe_count;
-                let height = mask_height / stripe_count;
-                let color = if i % 2 == 0 { &color1 } else { &color2 };
-
-                let mut rect = XMLElement::new("rect");
-                rect.add_attribute("x", "0");
-                rect.add_attribute("y", &y.to_string());
-                rect.add_attribute("width", &mask_width.to_string());
-                rect.add_attribute("height", &height.to_string());
-                rect.add_attribute("fill", color);
-
-                svg.add_child(rect).unwrap();
+/// Positions where the text of a [`MaskContent::Text`] mask is repeated
+struct TextGrid {
+    row_slide: i32,
+    offset_x: i32,
+    stride_x: u32,
+    offset_y: i32,
+    stride_y: u32,
+}
+
+impl TextGrid {
+    fn positions(&self, mask_width: u32, mask_height: u32) -> Vec<(i32, i32)> {
+        let mut positions = Vec::new();
+        let mut slide = 0;
+        for y in CoordinateIter::new(self.offset_y, self.stride_y, mask_height) {
+            for x in CoordinateIter::new(slide + self.offset_x, self.stride_x, mask_width) {
+                positions.push((x, y));
             }
+            slide += self.row_slide;
         }
-        MaskContent::Text {
-            text: mask_text,
+        positions
+    }
+}
+
+/// The text of a [`MaskContent::Text`] mask, with its sizes resolved for a particular mask size
+struct TextTile {
+    text: String,
+    style: TextStyle,
+    color: TextColor,
+    rotation: f32,
+    /// Size of the mask, which the decoration sizes are resolved against
+    mask_size: (u32, u32),
+    stroke: Option<TextStroke>,
+    shadow: Option<TextShadow>,
+    background: Option<TextBackground>,
+    grid: TextGrid,
+    jitter: Option<Jitter>,
+}
+
+impl TextTile {
+    /// Returns `None` for the masks that are not made of text
+    fn new(mask: &MaskConfig, mask_width: u32, mask_height: u32) -> Option<Self> {
+        let MaskContent::Text {
+            text,
             font,
+            font_weight,
+            font_style,
             color,
-            size_percent,
+            size,
+            min_size,
+            max_size,
+            letter_spacing,
+            line_height,
+            align,
             rotation,
-            row_slide_percent,
-            offset_x_percent,
-            stride_x_percent,
-            offset_y_percent,
-            stride_y_percent,
-        } => {
-            let color = color.hex_with_alpha(alpha);
-
-            let base_size = std::cmp::max(mask_width, mask_height) as f32 * 0.01;
-            let font_size = base_size * size_percent;
-            let font_size = format!("{font_size:.2}");
-
-            let row_slide = (base_size * row_slide_percent) as i32;
-            let offset_x = (base_size * offset_x_percent) as i32;
-            let stride_x = (base_size * stride_x_percent) as u32;
-            let offset_y = (base_size * offset_y_percent) as i32;
-            let stride_y = (base_size * stride_y_percent) as u32;
-
-            let mut slide = 0;
-            for y in CoordinateIter::new(offset_y, stride_y, mask_height) {
-                for x in CoordinateIter::new(slide + offset_x, stride_x, mask_width) {
-                    let mut text = XMLElement::new("text");
-                    text.add_attribute("x", &x.to_string());
-                    text.add_attribute("y", &y.to_string());
-                    text.add_attribute("fill", &color);
-                    text.add_attribute("font-family", &font);
-                    text.add_attribute("font-size", &font_size);
-                    text.add_attribute("transform", &format!("rotate({} {} {})", rotation, x, y));
-                    text.add_text(mask_text.clone()).unwrap();
-
-                    svg.add_child(text).unwrap();
-                }
-                slide += row_slide;
+            row_slide,
+            offset_x,
+            stride_x,
+            offset_y,
+            stride_y,
+            stroke,
+            shadow,
+            background,
+            jitter,
+        } = &mask.content
+        else {
+            return None;
+        };
+
+        let resolve = |length: &Length| length.resolve(mask_width, mask_height);
+
+        let mut font_size = resolve(size);
+        if let Some(max_size) = max_size {
+            font_size = font_size.min(resolve(max_size));
+        }
+        if let Some(min_size) = min_size {
+            font_size = font_size.max(resolve(min_size));
+        }
+
+        Some(Self {
+            text: text.clone(),
+            style: TextStyle {
+                family: font.clone(),
+                weight: *font_weight,
+                style: *font_style,
+                size: font_size,
+                letter_spacing: letter_spacing.as_ref().map_or(0.0, resolve),
+                line_height: *line_height,
+                align: *align,
+            },
+            color: color.clone(),
+            rotation: *rotation,
+            mask_size: (mask_width, mask_height),
+            stroke: stroke.clone(),
+            shadow: shadow.clone(),
+            background: background.clone(),
+            grid: TextGrid {
+                row_slide: resolve(row_slide) as i32,
+                offset_x: resolve(offset_x) as i32,
+                // a stride under a pixel would never get to the next copy
+                stride_x: resolve(stride_x).max(1.0) as u32,
+                offset_y: resolve(offset_y) as i32,
+                stride_y: resolve(stride_y).max(1.0) as u32,
+            },
+            jitter: jitter.as_ref().map(|jitter| Jitter {
+                // not seeded by the request, see `TextJitter::seed`
+                seed: jitter.seed.unwrap_or_default(),
+                offset: jitter.offset.as_ref().map_or(0.0, resolve),
+                rotation: jitter.rotation,
+                scale: jitter.scale,
+                opacity: jitter.opacity,
+            }),
+        })
+    }
+
+    /// Returns the length of a decoration in pixels
+    fn resolve(&self, length: &Length) -> f32 {
+        length.resolve(self.mask_size.0, self.mask_size.1)
+    }
+
+    /// Transformations the copies of the text are drawn with, just the plain text without the jitter
+    fn variants(&self) -> Vec<Variant> {
+        self.jitter
+            .as_ref()
+            .map_or_else(|| vec![Variant::default()], Jitter::variants)
+    }
+
+    /// Every copy of the text, using one of the variants
+    fn instances(&self, mask_width: u32, mask_height: u32, variant_count: usize) -> Vec<Instance> {
+        self.grid
+            .positions(mask_width, mask_height)
+            .into_iter()
+            .map(|(x, y)| match &self.jitter {
+                Some(jitter) => jitter.instance(x, y, variant_count),
+                None => Instance {
+                    x,
+                    y,
+                    variant: 0,
+                    opacity: 255,
+                },
+            })
+            .collect()
+    }
+}
+
+/// The stripes of a [`MaskContent::Stripes`] mask, empty for the other masks
+fn stripe_rects(mask: &MaskConfig, mask_width: u32, mask_height: u32) -> Vec<(Rect, &Color)> {
+    let MaskContent::Stripes {
+        color1,
+        color2,
+        stripe_count,
+    } = &mask.content
+    else {
+        return Vec::new();
+    };
+
+    (0..*stripe_count)
+        .filter_map(|i| {
+            let y = mask_height * i / stripe_count;
+            let height = mask_height / stripe_count;
+            let color = if i % 2 == 0 { color1 } else { color2 };
+
+            let rect = Rect::from_xywh(0.0, y as f32, mask_width as f32, height as f32)?;
+            Some((rect, color))
+        })
+        .collect()
+}
+
+/// Everything drawn for a single copy of the text, rotated around the start of its baseline
+struct TextLayers {
+    fill: Path,
+    stroke: Option<(Path, Color)>,
+    background: Option<(Path, Color)>,
+}
+
+impl TextLayers {
+    /// The part of the text that casts the shadow
+    fn silhouette(&self) -> impl Iterator<Item = &Path> {
+        std::iter::once(&self.fill).chain(self.stroke.as_ref().map(|(path, _)| path))
+    }
+
+    /// Bounds of everything drawn, including the blurred shadow
+    fn bounds(&self, tile: &TextTile) -> Option<Rect> {
+        let mut bounds = self
+            .silhouette()
+            .chain(self.background.as_ref().map(|(path, _)| path))
+            .map(|path| path.bounds())
+            .collect::<Vec<_>>();
+        if let Some(shadow) = &tile.shadow {
+            // the gaussian is practically zero further than three deviations away
+            let blur = tile.resolve(&shadow.blur) * 3.0;
+            for path in self.silhouette() {
+                let shadow_bounds = path.bounds().transform(shadow_offset(tile, shadow))?;
+                bounds.push(shadow_bounds.outset(blur, blur)?);
             }
         }
+
+        Rect::from_ltrb(
+            bounds.iter().map(|b| b.left()).reduce(f32::min)?,
+            bounds.iter().map(|b| b.top()).reduce(f32::min)?,
+            bounds.iter().map(|b| b.right()).reduce(f32::max)?,
+            bounds.iter().map(|b| b.bottom()).reduce(f32::max)?,
+        )
     }
+}
 
-    xml.set_root_element(svg);
+/// The shadow is offset in the image space, so that it falls the same way regardless of the text rotation
+fn shadow_offset(tile: &TextTile, shadow: &TextShadow) -> Transform {
+    Transform::from_translate(
+        tile.resolve(&shadow.offset_x),
+        tile.resolve(&shadow.offset_y),
+    )
+}
 
-    let mut svg = Vec::<u8>::new();
+/// A rectangle with the corners rounded with the radius
+fn rounded_rect(rect: Rect, radius: f32) -> Option<Path> {
+    let radius = radius.min(rect.width() / 2.0).min(rect.height() / 2.0);
+    if radius <= 0.0 {
+        return Some(PathBuilder::from_rect(rect));
+    }
 
-    xml.generate(&mut svg).unwrap();
+    // distance of the control points to approximate a quarter of a circle with a cubic
+    let k = radius * (1.0 - 0.552_284_8);
+    let (l, t, r, b) = (rect.left(), rect.top(), rect.right(), rect.bottom());
 
-    let svg = svg
-        .strip_prefix(br#"<?xml version="1.1" encoding="UTF-8"?>"#)
-        .unwrap();
-    std::str::from_utf8(svg).unwrap().to_string()
+    let mut builder = PathBuilder::new();
+    builder.move_to(l + radius, t);
+    builder.line_to(r - radius, t);
+    builder.cubic_to(r - k, t, r, t + k, r, t + radius);
+    builder.line_to(r, b - radius);
+    builder.cubic_to(r, b - k, r - k, b, r - radius, b);
+    builder.line_to(l + radius, b);
+    builder.cubic_to(l + k, b, l, b - k, l, b - radius);
+    builder.line_to(l, t + radius);
+    builder.cubic_to(l, t + k, l + k, t, l + radius, t);
+    builder.close();
+    builder.finish()
 }
 
-/// Renders a mask of a specified size and returns an RGBA image with _premultiplied_ alpha
-pub fn generate_mask(mask: MaskConfig, mask_width: u32, mask_height: u32) -> image::RgbaImage {
-    let svg_text = generate_mask_svg(mask, mask_width, mask_height);
+/// An opaque SVG `<path>`
+fn svg_path(path: &Path, color: &Color) -> XMLElement {
+    let mut element = XMLElement::new("path");
+    element.add_attribute("d", &svg_path_data(path));
+    element.add_attribute("fill", &color.hex_with_alpha(255));
+    element
+}
 
-    let svg_tree = {
-        let mut opt = usvg::Options::default();
+/// Formats the path as the `d` attribute of an SVG `<path>`
+fn svg_path_data(path: &Path) -> String {
+    let mut data = String::new();
+    for segment in path.segments() {
+        let command = match segment {
+            PathSegment::MoveTo(p) => format!("M{} {}", p.x, p.y),
+            PathSegment::LineTo(p) => format!("L{} {}", p.x, p.y),
+            PathSegment::QuadTo(p1, p) => format!("Q{} {} {} {}", p1.x, p1.y, p.x, p.y),
+            PathSegment::CubicTo(p1, p2, p) => {
+                format!("C{} {} {} {} {} {}", p1.x, p1.y, p2.x, p2.y, p.x, p.y)
+            }
+            PathSegment::Close => "Z".to_string(),
+        };
+        data.push_str(&command);
+    }
+    data
+}
 
-        opt.fontdb_mut()
-            .load_font_data(include_bytes!("../fonts/Comic Sans MS.ttf").into());
+/// A group with the layers of a single copy of the text, drawn like [`render_text_sprite`] does
+///
+/// The shadow refers to the `shadow` filter, which has to be defined along with the group
+fn svg_text(tile: &TextTile, layers: &TextLayers, id: &str, alpha: u8) -> XMLElement {
+    let mut text = XMLElement::new("g");
+    text.add_attribute("id", id);
+    text.add_attribute("opacity", &(alpha as f32 / 255.0).to_string());
 
-        usvg::Tree::from_str(&svg_text, &opt).unwrap()
-    };
+    if let Some((path, color)) = &layers.background {
+        text.add_child(svg_path(path, color)).unwrap();
+    }
+    if let Some(shadow) = &tile.shadow {
+        let offset = shadow_offset(tile, shadow);
+        let mut shadow_group = XMLElement::new("g");
+        shadow_group.add_attribute("filter", "url(#shadow)");
+        shadow_group.add_attribute(
+            "transform",
+            &format!("translate({} {})", offset.tx, offset.ty),
+        );
+        for path in layers.silhouette() {
+            shadow_group
+                .add_child(svg_path(path, &shadow.color))
+                .unwrap();
+        }
+        text.add_child(shadow_group).unwrap();
+    }
+    if let Some((path, color)) = &layers.stroke {
+        text.add_child(svg_path(path, color)).unwrap();
+    }
+    text.add_child(svg_path(&layers.fill, tile.color.default_color()))
+        .unwrap();
+    text
+}
 
-    let pixmap_size = IntSize::from_wh(mask_width, mask_height).unwrap();
-    let mut pixmap = tiny_skia::Pixmap::new(pixmap_size.width(), pixmap_size.height()).unwrap();
-    resvg::render(
-        &svg_tree,
-        tiny_skia::Transform::default(),
-        &mut pixmap.as_mut(),
+/// Draws the layers of a single copy of the text into a sprite with the bounds, with the text filled with the color
+///
+/// The layers are drawn opaque, the alpha is applied to the whole sprite later, so that they don't show through each other
+fn render_text_sprite(
+    tile: &TextTile,
+    layers: &TextLayers,
+    color: &Color,
+    left: i32,
+    top: i32,
+    width: u32,
+    height: u32,
+) -> Option<tiny_skia::Pixmap> {
+    let mut sprite = tiny_skia::Pixmap::new(width, height)?;
+    let to_sprite = Transform::from_translate(-left as f32, -top as f32);
+
+    if let Some((background, color)) = &layers.background {
+        sprite.fill_path(
+            background,
+            &color.paint(255),
+            FillRule::Winding,
+            to_sprite,
+            None,
+        );
+    }
+    if let Some(shadow) = &tile.shadow {
+        let mut shadow_layer = tiny_skia::Pixmap::new(width, height)?;
+        let transform = to_sprite.pre_concat(shadow_offset(tile, shadow));
+        let paint = shadow.color.paint(255);
+        for path in layers.silhouette() {
+            shadow_layer.fill_path(path, &paint, FillRule::Winding, transform, None);
+        }
+        blur::gaussian_blur(&mut shadow_layer, tile.resolve(&shadow.blur));
+
+        sprite.draw_pixmap(
+            0,
+            0,
+            shadow_layer.as_ref(),
+            &PixmapPaint::default(),
+            Transform::identity(),
+            None,
+        );
+    }
+    if let Some((stroke, color)) = &layers.stroke {
+        sprite.fill_path(
+            stroke,
+            &color.paint(255),
+            FillRule::Winding,
+            to_sprite,
+            None,
+        );
+    }
+    sprite.fill_path(
+        &layers.fill,
+        &color.paint(255),
+        FillRule::Winding,
+        to_sprite,
+        None,
     );
 
-    image::RgbaImage::from_raw(mask_width, mask_height, pixmap.take()).unwrap()
+    Some(sprite)
+}
+
+/// Renders masks and applies them to images
+///
+/// Holds the state that is expensive to create and can be shared between renders, like the font database.
+pub struct MaskRenderer {
+    fontdb: Arc<fontdb::Database>,
+}
+
+impl MaskRenderer {
+    pub fn new() -> Self {
+        let mut fontdb = fontdb::Database::new();
+        fontdb.load_font_data(include_bytes!("../fonts/Comic Sans MS.ttf").into());
+
+        Self {
+            fontdb: Arc::new(fontdb),
+        }
+    }
+
+    /// Lays out a single copy of the text with its decorations, transformed as the variant
+    fn text_layers(&self, tile: &TextTile, variant: &Variant) -> Option<TextLayers> {
+        let fill = text::outline(&self.fontdb, &tile.text, &tile.style)?;
+        let stroke = tile.stroke.as_ref().and_then(|stroke| {
+            let width = tile.resolve(&stroke.width);
+            let path = fill.stroke(
+                &tiny_skia::Stroke {
+                    width,
+                    line_join: LineJoin::Round,
+                    ..Default::default()
+                },
+                1.0,
+            )?;
+            Some((path, stroke.color.clone()))
+        });
+        let background = tile.background.as_ref().and_then(|background| {
+            let padding = tile.resolve(&background.padding);
+            let glyphs = stroke.as_ref().map_or(&fill, |(path, _)| path).bounds();
+            let path = rounded_rect(
+                glyphs.outset(padding, padding)?,
+                tile.resolve(&background.corner_radius),
+            )?;
+            Some((path, background.color.clone()))
+        });
+
+        let transform = Transform::from_rotate(tile.rotation + variant.rotation)
+            .pre_scale(variant.scale, variant.scale);
+        let apply = |(path, color): (Path, Color)| Some((path.transform(transform)?, color));
+        Some(TextLayers {
+            fill: fill.transform(transform)?,
+            stroke: stroke.and_then(apply),
+            background: background.and_then(apply),
+        })
+    }
+
+    /// Renders a mask of a specified size and returns an RGBA image with _premultiplied_ alpha
+    ///
+    /// The image is not known here, so the automatic text colors use the light color
+    pub fn generate_mask(
+        &self,
+        mask: MaskConfig,
+        mask_width: u32,
+        mask_height: u32,
+    ) -> image::RgbaImage {
+        self.generate_mask_over(mask, mask_width, mask_height, None)
+    }
+
+    /// Renders a mask for the image with the luminance, if it's known
+    fn generate_mask_over(
+        &self,
+        mask: MaskConfig,
+        mask_width: u32,
+        mask_height: u32,
+        luminance: Option<&Luminance>,
+    ) -> image::RgbaImage {
+        let pixmap_size = IntSize::from_wh(mask_width, mask_height).unwrap();
+        let mut pixmap = tiny_skia::Pixmap::new(pixmap_size.width(), pixmap_size.height()).unwrap();
+
+        for (rect, color) in stripe_rects(&mask, mask_width, mask_height) {
+            let paint = color.paint(mask.alpha);
+            pixmap.fill_rect(rect, &paint, Transform::identity(), None);
+        }
+        if let Some(tile) = TextTile::new(&mask, mask_width, mask_height) {
+            self.stamp_text(&tile, mask.alpha, luminance, &mut pixmap);
+        }
+
+        image::RgbaImage::from_raw(mask_width, mask_height, pixmap.take()).unwrap()
+    }
+
+    /// Renders each variant of the text into a sprite once and stamps it at the positions of its copies
+    ///
+    /// Laying out and rasterizing the text is the expensive part, so it's done only once per variant instead of for every copy.
+    /// The sprite is aligned to the pixel grid and the positions are whole pixels,
+    /// so the stamps are the same as the text rendered in place.
+    fn stamp_text(
+        &self,
+        tile: &TextTile,
+        alpha: u8,
+        luminance: Option<&Luminance>,
+        pixmap: &mut tiny_skia::Pixmap,
+    ) {
+        let variants = tile.variants();
+        let instances = tile.instances(pixmap.width(), pixmap.height(), variants.len());
+
+        for (index, variant) in variants.iter().enumerate() {
+            let copies = instances
+                .iter()
+                .filter(|instance| instance.variant == index)
+                .collect::<Vec<_>>();
+            if !copies.is_empty() {
+                self.stamp_variant(tile, variant, &copies, alpha, luminance, pixmap);
+            }
+        }
+    }
+
+    /// Stamps the copies of the text that use the variant
+    ///
+    /// With the automatic color, there is a sprite for each of the colors, and each copy uses the one that stands out more
+    fn stamp_variant(
+        &self,
+        tile: &TextTile,
+        variant: &Variant,
+        copies: &[&Instance],
+        alpha: u8,
+        luminance: Option<&Luminance>,
+        pixmap: &mut tiny_skia::Pixmap,
+    ) {
+        let Some(layers) = self.text_layers(tile, variant) else {
+            return;
+        };
+        let Some(bounds) = layers.bounds(tile) else {
+            return;
+        };
+
+        // one pixel of margin for the anti-aliasing
+        let left = bounds.left().floor() as i32 - 1;
+        let top = bounds.top().floor() as i32 - 1;
+        let width = (bounds.right().ceil() as i32 + 1 - left) as u32;
+        let height = (bounds.bottom().ceil() as i32 + 1 - top) as u32;
+        let render = |color: &Color| {
+            let sprite = render_text_sprite(tile, &layers, color, left, top, width, height)?;
+            Some(Sprite::new(sprite, alpha))
+        };
+
+        let stamps = copies
+            .iter()
+            .map(|copy| Stamp {
+                x: copy.x + left,
+                y: copy.y + top,
+                opacity: copy.opacity,
+            })
+            .collect::<Vec<_>>();
+
+        match (&tile.color, luminance) {
+            (TextColor::Auto { light, dark }, Some(luminance)) => {
+                let (Some(light_sprite), Some(dark_sprite)) = (render(light), render(dark)) else {
+                    return;
+                };
+
+                let (light_stamps, dark_stamps) =
+                    stamps.into_iter().partition::<Vec<_>, _>(|stamp| {
+                        // the sprites only differ in color, so either one can be used to find what's under the text
+                        let Some(background) = luminance.under(&light_sprite, stamp.x, stamp.y)
+                        else {
+                            return true;
+                        };
+                        (light.luma() - background).abs() >= (dark.luma() - background).abs()
+                    });
+                light_sprite.stamp(pixmap, &light_stamps);
+                dark_sprite.stamp(pixmap, &dark_stamps);
+            }
+            (color, _) => {
+                if let Some(sprite) = render(color.default_color()) {
+                    sprite.stamp(pixmap, &stamps);
+                }
+            }
+        }
+    }
+
+    /// Exports the mask as an SVG document, for debugging
+    ///
+    /// The text is exported as the same outlines that are rendered, so the document doesn't depend on the fonts.
+    /// Like [`MaskRenderer::generate_mask`], the automatic text colors use the light color
+    pub fn export_svg(&self, mask: MaskConfig, mask_width: u32, mask_height: u32) -> String {
+        let mut svg = XMLElement::new("svg");
+        svg.add_attribute("xmlns", "http://www.w3.org/2000/svg");
+        svg.add_attribute("width", &mask_width.to_string());
+        svg.add_attribute("height", &mask_height.to_string());
+        svg.add_attribute("viewBox", &format!("0 0 {mask_width} {mask_height}"));
+
+        for (rect, color) in stripe_rects(&mask, mask_width, mask_height) {
+            let mut element = XMLElement::new("rect");
+            element.add_attribute("x", &rect.x().to_string());
+            element.add_attribute("y", &rect.y().to_string());
+            element.add_attribute("width", &rect.width().to_string());
+            element.add_attribute("height", &rect.height().to_string());
+            element.add_attribute("fill", &color.hex_with_alpha(mask.alpha));
+            svg.add_child(element).unwrap();
+        }
+
+        if let Some(tile) = TextTile::new(&mask, mask_width, mask_height) {
+            let mut defs = XMLElement::new("defs");
+            if let Some(shadow) = &tile.shadow {
+                let mut filter = XMLElement::new("filter");
+                filter.add_attribute("id", "shadow");
+                // the default filter region is too small for a large blur
+                filter.add_attribute("x", "-1");
+                filter.add_attribute("y", "-1");
+                filter.add_attribute("width", "3");
+                filter.add_attribute("height", "3");
+                let mut blur = XMLElement::new("feGaussianBlur");
+                let deviation = tile.resolve(&shadow.blur);
+                blur.add_attribute("stdDeviation", &deviation.to_string());
+                filter.add_child(blur).unwrap();
+                defs.add_child(filter).unwrap();
+            }
+
+            // each variant of the text is defined once and then referenced by its copies
+            let variants = tile.variants();
+            let mut defined = vec![false; variants.len()];
+            for (index, variant) in variants.iter().enumerate() {
+                if let Some(layers) = self.text_layers(&tile, variant) {
+                    let id = format!("text-{index}");
+                    defs.add_child(svg_text(&tile, &layers, &id, mask.alpha))
+                        .unwrap();
+                    defined[index] = true;
+                }
+            }
+            svg.add_child(defs).unwrap();
+
+            for instance in tile.instances(mask_width, mask_height, variants.len()) {
+                if !defined[instance.variant] {
+                    continue;
+                }
+                let mut element = XMLElement::new("use");
+                element.add_attribute("href", &format!("#text-{}", instance.variant));
+                element.add_attribute("x", &instance.x.to_string());
+                element.add_attribute("y", &instance.y.to_string());
+                if instance.opacity != 255 {
+                    let opacity = instance.opacity as f32 / 255.0;
+                    element.add_attribute("opacity", &opacity.to_string());
+                }
+                svg.add_child(element).unwrap();
+            }
+        }
+
+        let mut xml = XMLBuilder::new()
+            .version(XMLVersion::XML1_1)
+            .encoding("UTF-8".into())
+            .build();
+        xml.set_root_element(svg);
+
+        let mut result = Vec::<u8>::new();
+        xml.generate(&mut result).unwrap();
+        String::from_utf8(result).unwrap()
+    }
+
+    /// Renders the mask and composites it over the image
+    ///
+    /// Fails if the cancellation was requested midway, leaving the image partially masked
+    pub fn apply_mask<I, P, T>(
+        &self,
+        mask: MaskConfig,
+        image: &mut I,
+        cancel: &CancellationToken,
+    ) -> Result<(), Cancelled>
+    where
+        I: GenericImage<Pixel = P>,
+        P: Pixel<Subpixel = T> + FromRgba,
+        T: Primitive,
+    {
+        let compositing = Compositing {
+            blend_mode: mask.blend_mode,
+            color_space: mask.color_space,
+        };
+        let luminance = mask.needs_image().then(|| Luminance::new(image));
+        let mask = self.generate_mask_over(mask, image.width(), image.height(), luminance.as_ref());
+        cancel.check()?;
+
+        composite_mask(&mask, image, compositing, cancel)
+    }
+
+    /// Same as [`MaskRenderer::apply_mask`], but much faster for the 8-bit RGB and RGBA images
+    ///
+    /// Only the plain src-over in sRGB has the fast path, the other compositing options fall back to the generic one
+    pub fn apply_mask_fast<P: FastPixel + FromRgba>(
+        &self,
+        mask: MaskConfig,
+        image: &mut ImageBuffer<P, Vec<u8>>,
+        cancel: &CancellationToken,
+    ) -> Result<(), Cancelled> {
+        let compositing = Compositing {
+            blend_mode: mask.blend_mode,
+            color_space: mask.color_space,
+        };
+        let luminance = mask.needs_image().then(|| Luminance::new(image));
+        let mask = self.generate_mask_over(mask, image.width(), image.height(), luminance.as_ref());
+        cancel.check()?;
+
+        if compositing == Compositing::default() {
+            composite_mask_fast(&mask, image, cancel)
+        } else {
+            composite_mask(&mask, image, compositing, cancel)
+        }
+    }
+}
+
+impl Default for MaskRenderer {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 pub trait FromRgba {
@@ -236,17 +1061,34 @@ impl FromRgba for Rgb<u8> {
     }
 }
 
-pub fn apply_mask<I, P, T>(mask: MaskConfig, image: &mut I)
+/// How the mask is combined with the image
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Compositing {
+    pub blend_mode: BlendMode,
+    pub color_space: ColorSpace,
+}
+
+/// Composites a premultiplied RGBA mask over the image using src-over, blending the colors with the blend mode
+pub fn composite_mask<I, P, T>(
+    mask: &image::RgbaImage,
+    image: &mut I,
+    Compositing {
+        blend_mode,
+        color_space,
+    }: Compositing,
+    cancel: &CancellationToken,
+) -> Result<(), Cancelled>
 where
     I: GenericImage<Pixel = P>,
     P: Pixel<Subpixel = T> + FromRgba,
     T: Primitive,
 {
-    let mask = generate_mask(mask, image.width(), image.height());
-
     assert_eq!(image.width(), mask.width());
+    assert_eq!(image.height(), mask.height());
 
     for (y, mask_row) in (0..).zip(mask.rows()) {
+        cancel.check()?;
+
         for (x, &fg_pix) in (0..).zip(mask_row) {
             // SAFETY: the mask has the same size as the image
             let bg_pix = unsafe { image.unsafe_get_pixel(x, y) };
@@ -271,6 +1113,27 @@ where
                 fg_a.to_f32().unwrap() / u8::MAX as f32,
             );
 
+            let (bg_r, bg_g, bg_b, fg_r, fg_g, fg_b) = match color_space {
+                ColorSpace::Srgb => (bg_r, bg_g, bg_b, fg_r, fg_g, fg_b),
+                // the fg is premultiplied, so it has to be unmultiplied to be decoded
+                ColorSpace::Linear if fg_a == 0.0 => (
+                    srgb_to_linear(bg_r),
+                    srgb_to_linear(bg_g),
+                    srgb_to_linear(bg_b),
+                    0.0,
+                    0.0,
+                    0.0,
+                ),
+                ColorSpace::Linear => (
+                    srgb_to_linear(bg_r),
+                    srgb_to_linear(bg_g),
+                    srgb_to_linear(bg_b),
+                    srgb_to_linear(fg_r / fg_a) * fg_a,
+                    srgb_to_linear(fg_g / fg_a) * fg_a,
+                    srgb_to_linear(fg_b / fg_a) * fg_a,
+                ),
+            };
+
             // Work out what the final alpha level will be
             let alpha_final = bg_a + fg_a - bg_a * fg_a;
             // if alpha_final == 0.0 {
@@ -280,7 +1143,17 @@ where
             // We premultiply our channels by their alpha, as this makes it easier to calculate
             let (bg_r_a, bg_g_a, bg_b_a) = (bg_r * bg_a, bg_g * bg_a, bg_b * bg_a);
             // the fg is already premultiplied
-            let (fg_r_a, fg_g_a, fg_b_a) = (fg_r, fg_g, fg_b);
+            let (fg_r_a, fg_g_a, fg_b_a) = if blend_mode == BlendMode::Normal || fg_a == 0.0 {
+                (fg_r, fg_g, fg_b)
+            } else {
+                // the blend mode works with unpremultiplied colors.
+                // where the backdrop is transparent, the source color is used as is
+                let source = [fg_r / fg_a, fg_g / fg_a, fg_b / fg_a];
+                let blended = blend_mode.blend([bg_r, bg_g, bg_b], source);
+                let [r, g, b] = [0, 1, 2]
+                    .map(|i| ((1.0 - bg_a) * source[i] + bg_a * blended[i]).clamp(0.0, 1.0) * fg_a);
+                (r, g, b)
+            };
 
             // Standard formula for src-over alpha compositing
             let (out_r_a, out_g_a, out_b_a) = (
@@ -301,6 +1174,14 @@ where
                 if out_g.is_finite() { out_g } else { 0.0 },
                 if out_b.is_finite() { out_b } else { 0.0 },
             );
+            let (out_r, out_g, out_b) = match color_space {
+                ColorSpace::Srgb => (out_r, out_g, out_b),
+                ColorSpace::Linear => (
+                    linear_to_srgb(out_r),
+                    linear_to_srgb(out_g),
+                    linear_to_srgb(out_b),
+                ),
+            };
 
             // Cast back to our initial type on return
             let im_pix = Rgba::<u8>([
@@ -318,6 +1199,8 @@ where
             }
         }
     }
+
+    Ok(())
 }
 
 // const TEST_MASK: Mask = Mask::Stripes {
@@ -328,51 +1211,546 @@ where
 
 #[cfg(test)]
 mod tests {
-    use super::{apply_mask, generate_mask, generate_mask_svg, Color, MaskConfig, MaskContent};
+    use super::{
+        composite_mask, composite_mask_fast, BlendMode, Color, ColorSpace, Compositing, FontStyle,
+        Length, LengthUnit, MaskConfig, MaskContent, MaskRenderer, TextAlign, TextBackground,
+        TextColor, TextJitter, TextShadow, TextStroke, TextTile,
+    };
+    use crate::cancellation::CancellationToken;
+
+    fn long(value: f32) -> Length {
+        Length {
+            value,
+            unit: LengthUnit::Long,
+        }
+    }
 
     fn get_test_mask() -> MaskConfig {
         MaskConfig {
             alpha: 32,
+            blend_mode: BlendMode::Normal,
+            color_space: ColorSpace::Srgb,
             content: MaskContent::Text {
                 text: "ЧУПЛЫГИН УХОДИ".to_string(),
                 font: "Comic Sans MS".to_string(),
-                color: Color {
+                font_weight: 400,
+                font_style: FontStyle::Normal,
+                color: TextColor::Fixed(Color {
                     rgb: [0xff, 0xff, 0xff],
-                },
-                size_percent: 5.0,
+                }),
+                size: long(5.0),
+                min_size: None,
+                max_size: None,
+                letter_spacing: None,
+                line_height: 1.2,
+                align: TextAlign::Left,
                 rotation: 45.0,
-                row_slide_percent: 1.0,
-                offset_x_percent: -30.0,
-                stride_x_percent: 30.0,
-                offset_y_percent: -20.0,
-                stride_y_percent: 20.0,
+                row_slide: long(1.0),
+                offset_x: long(-30.0),
+                stride_x: long(30.0),
+                offset_y: long(-20.0),
+                stride_y: long(20.0),
+                stroke: None,
+                shadow: None,
+                background: None,
+                jitter: None,
             },
         }
     }
 
     #[test]
     fn svg_smoke() {
-        let svg = generate_mask_svg(get_test_mask(), 100, 100);
+        let svg = MaskRenderer::new().export_svg(get_test_mask(), 100, 100);
 
         eprintln!("{}", svg);
     }
 
     #[test]
     fn generate_mask_smoke() {
-        let mask = generate_mask(get_test_mask(), 720, 1920);
+        let mask = MaskRenderer::new().generate_mask(get_test_mask(), 720, 1920);
 
         mask.save("example_results/mask_premultiplied_720x1920.png")
             .unwrap();
     }
 
+    #[test]
+    fn exported_svg_matches_render() {
+        let (width, height) = (400, 300);
+        // markup in the text must not leak into the document
+        let mask = get_test_mask()
+            .with_text("</text><rect width=\"100%\" height=\"100%\"/>")
+            .unwrap();
+        let renderer = MaskRenderer::new();
+        let svg = renderer.export_svg(mask.clone(), width, height);
+        assert!(!svg.contains("<rect"));
+
+        assert_svg_matches_render(&renderer, mask, width, height);
+    }
+
+    fn assert_svg_matches_render(
+        renderer: &MaskRenderer,
+        mask: MaskConfig,
+        width: u32,
+        height: u32,
+    ) {
+        let rendered = renderer.generate_mask(mask.clone(), width, height);
+
+        let svg = renderer.export_svg(mask, width, height);
+        let tree = usvg::Tree::from_str(&svg, &usvg::Options::default()).unwrap();
+        let mut pixmap = tiny_skia::Pixmap::new(width, height).unwrap();
+        resvg::render(&tree, tiny_skia::Transform::default(), &mut pixmap.as_mut());
+
+        // the copies of the text are rasterized separately and at other offsets, so the anti-aliasing differs a bit
+        for (rendered, exported) in rendered.as_raw().iter().zip(pixmap.data()) {
+            assert!(rendered.abs_diff(*exported) <= 8);
+        }
+        assert!(rendered.pixels().any(|p| p[3] != 0));
+    }
+
+    #[test]
+    fn jittered_text() {
+        let (width, height) = (400, 300);
+        let renderer = MaskRenderer::new();
+        let jittered = |seed| {
+            let mut mask = get_test_mask();
+            let MaskContent::Text { jitter, .. } = &mut mask.content else {
+                unreachable!()
+            };
+            *jitter = Some(TextJitter {
+                seed,
+                offset: Some("10px".parse().unwrap()),
+                rotation: 20.0,
+                scale: 0.2,
+                opacity: 0.5,
+            });
+            mask
+        };
+        let render = |mask: MaskConfig| renderer.generate_mask(mask, width, height);
+
+        // the same request gives the same mask, and another request a different one
+        let first = render(jittered(None).with_request_seed(1));
+        assert_eq!(first, render(jittered(None).with_request_seed(1)));
+        assert_ne!(first, render(jittered(None).with_request_seed(2)));
+        assert_ne!(first, render(get_test_mask()));
+
+        // the seed of the preset is used over the one of the request
+        assert_eq!(
+            render(jittered(Some(5)).with_request_seed(1)),
+            render(jittered(Some(5)).with_request_seed(2))
+        );
+
+        assert_svg_matches_render(&renderer, jittered(Some(5)), width, height);
+
+        let validate = |change: fn(&mut TextJitter)| {
+            let mut mask = jittered(None);
+            if let MaskContent::Text {
+                jitter: Some(jitter),
+                ..
+            } = &mut mask.content
+            {
+                change(jitter);
+            }
+            mask.validate().map_err(|e| e.to_string())
+        };
+        let error = |message: &str| Err(message.to_string());
+        assert_eq!(validate(|_| {}), Ok(()));
+        assert_eq!(
+            validate(|j| j.scale = 1.0),
+            error("`jitter.scale` must be at least 0 and less than 1")
+        );
+        assert_eq!(
+            validate(|j| j.scale = -0.1),
+            error("`jitter.scale` must be at least 0 and less than 1")
+        );
+        assert_eq!(
+            validate(|j| j.rotation = -5.0),
+            error("`jitter.rotation` can't be negative")
+        );
+        assert_eq!(
+            validate(|j| j.opacity = -0.5),
+            error("`jitter.opacity` must be between 0 and 1")
+        );
+        assert_eq!(
+            validate(|j| j.offset = Some("-10px".parse().unwrap())),
+            error("`jitter.offset` can't be negative")
+        );
+    }
+
+    #[test]
+    fn synthetic_font_style() {
+        let (width, height) = (400, 300);
+        let renderer = MaskRenderer::new();
+        let styled = |weight, style| {
+            let mut mask = get_test_mask();
+            let MaskContent::Text {
+                font_weight,
+                font_style,
+                ..
+            } = &mut mask.content
+            else {
+                unreachable!()
+            };
+            (*font_weight, *font_style) = (weight, style);
+            mask
+        };
+        let covered = |mask: MaskConfig| {
+            let mask = renderer.generate_mask(mask, width, height);
+            mask.pixels().filter(|p| p[3] != 0).count()
+        };
+
+        // only the regular face is bundled, so the bold and the italic are synthesized
+        let regular = covered(styled(400, FontStyle::Normal));
+        assert!(covered(styled(700, FontStyle::Normal)) > regular);
+        assert!(covered(styled(900, FontStyle::Normal)) > covered(styled(700, FontStyle::Normal)));
+        assert_ne!(
+            renderer.generate_mask(styled(400, FontStyle::Italic), width, height),
+            renderer.generate_mask(styled(400, FontStyle::Normal), width, height)
+        );
+
+        assert_svg_matches_render(&renderer, styled(700, FontStyle::Italic), width, height);
+    }
+
+    #[test]
+    fn text_stride() {
+        let with_strides = |x: &str, y: &str| {
+            let mut mask = get_test_mask();
+            let MaskContent::Text {
+                stride_x, stride_y, ..
+            } = &mut mask.content
+            else {
+                unreachable!()
+            };
+            (*stride_x, *stride_y) = (x.parse().unwrap(), y.parse().unwrap());
+            mask
+        };
+
+        // the strides that round down to nothing still move on to the next copy
+        let mask = with_strides("0.5px", "0.01%short");
+        mask.validate().unwrap();
+        let tile = TextTile::new(&mask, 100, 100).unwrap();
+        assert_eq!((tile.grid.stride_x, tile.grid.stride_y), (1, 1));
+
+        let error = with_strides("-10px", "20").validate().unwrap_err();
+        assert_eq!(error.to_string(), "`stride_x` must be positive");
+        let error = with_strides("30", "0%height").validate().unwrap_err();
+        assert_eq!(error.to_string(), "`stride_y` must be positive");
+    }
+
+    #[test]
+    fn text_size_validation() {
+        let validate = |size: &str, min_size: Option<&str>, max_size: Option<&str>, height| {
+            let mut mask = get_test_mask();
+            let MaskContent::Text {
+                size: mask_size,
+                min_size: mask_min_size,
+                max_size: mask_max_size,
+                line_height,
+                ..
+            } = &mut mask.content
+            else {
+                unreachable!()
+            };
+            *mask_size = size.parse().unwrap();
+            *mask_min_size = min_size.map(|s| s.parse().unwrap());
+            *mask_max_size = max_size.map(|s| s.parse().unwrap());
+            *line_height = height;
+            mask.validate().map_err(|e| e.to_string())
+        };
+
+        assert_eq!(validate("5", Some("12px"), Some("200px"), 1.2), Ok(()));
+        // the units differ, so it depends on the image which one is larger
+        assert_eq!(validate("5", Some("10%short"), Some("40px"), 1.2), Ok(()));
+
+        let error = |message: &str| Err(message.to_string());
+        assert_eq!(
+            validate("-5", None, None, 1.2),
+            error("`size` must be positive")
+        );
+        assert_eq!(
+            validate("0px", None, None, 1.2),
+            error("`size` must be positive")
+        );
+        assert_eq!(
+            validate("5", Some("-12px"), None, 1.2),
+            error("`min_size` must be positive")
+        );
+        assert_eq!(
+            validate("5", None, Some("0"), 1.2),
+            error("`max_size` must be positive")
+        );
+        assert_eq!(
+            validate("5", Some("200px"), Some("12px"), 1.2),
+            error("`min_size` can't be larger than `max_size`")
+        );
+        assert_eq!(
+            validate("5", None, None, 0.0),
+            error("`line_height` must be positive")
+        );
+        assert_eq!(
+            validate("5", None, None, -1.0),
+            error("`line_height` must be positive")
+        );
+    }
+
+    #[test]
+    fn text_size_units() {
+        let font_size = |size: &str, min_size: Option<&str>, width, height| {
+            let mut mask = get_test_mask();
+            let MaskContent::Text {
+                size: mask_size,
+                min_size: mask_min_size,
+                max_size,
+                ..
+            } = &mut mask.content
+            else {
+                unreachable!()
+            };
+            *mask_size = size.parse().unwrap();
+            *mask_min_size = min_size.map(|s| s.parse().unwrap());
+            *max_size = Some("200px".parse().unwrap());
+            TextTile::new(&mask, width, height).unwrap().style.size
+        };
+
+        assert_eq!(font_size("40px", None, 4000, 1000), 40.0);
+        // the same relative size on a panorama and on a square
+        assert_eq!(font_size("5%short", None, 4000, 1000), 50.0);
+        assert_eq!(font_size("5%short", None, 1000, 1000), 50.0);
+        assert_eq!(font_size("5", None, 4000, 1000), 200.0);
+
+        // clamped on the images that are too small or too large
+        assert_eq!(font_size("5%short", Some("12px"), 100, 100), 12.0);
+        assert_eq!(font_size("5%short", Some("12px"), 8000, 8000), 200.0);
+    }
+
+    #[test]
+    fn text_decorations() {
+        let (width, height) = (400, 300);
+        let renderer = MaskRenderer::new();
+        // a single copy of the text, so that the copies don't overlap
+        let single = |stroke, shadow, background| {
+            let mut mask = get_test_mask();
+            let MaskContent::Text {
+                offset_x,
+                offset_y,
+                stride_x,
+                stride_y,
+                stroke: mask_stroke,
+                shadow: mask_shadow,
+                background: mask_background,
+                ..
+            } = &mut mask.content
+            else {
+                unreachable!()
+            };
+            (*offset_x, *offset_y) = (long(10.0), long(10.0));
+            (*stride_x, *stride_y) = (long(1000.0), long(1000.0));
+            (*mask_stroke, *mask_shadow, *mask_background) = (stroke, shadow, background);
+            renderer.generate_mask(mask, width, height)
+        };
+        let black = || Color { rgb: [0, 0, 0] };
+        let covered = |mask: &image::RgbaImage| mask.pixels().filter(|p| p[3] != 0).count();
+
+        let plain = single(None, None, None);
+        let stroked = single(
+            Some(TextStroke {
+                color: black(),
+                width: long(1.0),
+            }),
+            None,
+            None,
+        );
+        let shadowed = single(
+            None,
+            Some(TextShadow {
+                color: black(),
+                offset_x: long(1.0),
+                offset_y: long(1.0),
+                blur: long(0.5),
+            }),
+            None,
+        );
+        let boxed = single(
+            None,
+            None,
+            Some(TextBackground {
+                color: black(),
+                padding: long(1.0),
+                corner_radius: long(1.0),
+            }),
+        );
+
+        assert!(covered(&stroked) > covered(&plain));
+        assert!(covered(&shadowed) > covered(&plain));
+        assert!(covered(&boxed) > covered(&shadowed));
+        for mask in [&plain, &stroked, &shadowed, &boxed] {
+            // the layers are not more opaque than the preset alpha, even where they overlap
+            assert_eq!(mask.pixels().map(|p| p[3]).max(), Some(32));
+        }
+        // the text stays white over the black box
+        assert!(boxed.pixels().any(|p| p.0 == [32, 32, 32, 32]));
+        assert!(boxed.pixels().any(|p| p.0 == [0, 0, 0, 32]));
+    }
+
+    #[test]
+    fn auto_text_color() {
+        let mut mask = get_test_mask();
+        mask.alpha = 255;
+        let MaskContent::Text { color, .. } = &mut mask.content else {
+            unreachable!()
+        };
+        *color = TextColor::Auto {
+            light: Color { rgb: [255, 0, 0] },
+            dark: Color { rgb: [0, 0, 255] },
+        };
+
+        // the left half is black, the right one is white
+        let mut image = image::RgbImage::from_fn(800, 400, |x, _| {
+            if x < 400 {
+                image::Rgb([0, 0, 0])
+            } else {
+                image::Rgb([255, 255, 255])
+            }
+        });
+        MaskRenderer::new()
+            .apply_mask_fast(mask, &mut image, &CancellationToken::default())
+            .unwrap();
+
+        let has_color = |xs: std::ops::Range<u32>, color| {
+            xs.flat_map(|x| (0..400).map(move |y| (x, y)))
+                .any(|(x, y)| image.get_pixel(x, y).0 == color)
+        };
+        // the copies far enough from the middle are entirely on one side
+        assert!(has_color(0..200, [255, 0, 0]));
+        assert!(!has_color(0..200, [0, 0, 255]));
+        assert!(has_color(600..800, [0, 0, 255]));
+        assert!(!has_color(600..800, [255, 0, 0]));
+    }
+
+    #[test]
+    fn apply_mask_cancelled() {
+        let cancel = CancellationToken::default();
+        cancel.cancel();
+
+        let mut image = image::RgbImage::new(100, 100);
+        let result = MaskRenderer::new().apply_mask(get_test_mask(), &mut image, &cancel);
+
+        assert!(result.is_err());
+        assert!(image.pixels().all(|p| p.0 == [0, 0, 0]));
+    }
+
+    #[test]
+    fn composite_blend_mode() {
+        // half-transparent white, premultiplied
+        let mask = image::RgbaImage::from_pixel(1, 1, image::Rgba([128, 128, 128, 128]));
+        let composite = |blend_mode| {
+            let mut image = image::RgbImage::from_pixel(1, 1, image::Rgb([0, 100, 200]));
+            let compositing = Compositing {
+                blend_mode,
+                ..Default::default()
+            };
+            composite_mask(
+                &mask,
+                &mut image,
+                compositing,
+                &CancellationToken::default(),
+            )
+            .unwrap();
+            image.get_pixel(0, 0).0
+        };
+
+        assert_eq!(composite(BlendMode::Normal), [128, 177, 227]);
+        // multiplying by white doesn't change anything
+        assert_eq!(composite(BlendMode::Multiply), [0, 100, 200]);
+        assert_eq!(composite(BlendMode::Difference), [128, 127, 127]);
+    }
+
+    #[test]
+    fn composite_linear_light() {
+        // reference values are from compositing in linear light with the exact sRGB transfer function
+        let cases = [
+            // 50% white over black
+            ([128, 128, 128, 128], [0, 0, 0], [188, 188, 188]),
+            // 25% white over mid-gray
+            ([64, 64, 64, 64], [128, 128, 128], [172, 172, 172]),
+            // 50% red over green
+            ([128, 0, 0, 128], [0, 255, 0], [188, 188, 0]),
+            // transparent mask doesn't change anything
+            ([0, 0, 0, 0], [12, 34, 56], [12, 34, 56]),
+        ];
+
+        for (mask_pixel, image_pixel, expected) in cases {
+            let mask = image::RgbaImage::from_pixel(1, 1, image::Rgba(mask_pixel));
+            let mut image = image::RgbImage::from_pixel(1, 1, image::Rgb(image_pixel));
+            let compositing = Compositing {
+                color_space: ColorSpace::Linear,
+                ..Default::default()
+            };
+            composite_mask(
+                &mask,
+                &mut image,
+                compositing,
+                &CancellationToken::default(),
+            )
+            .unwrap();
+
+            let actual = image.get_pixel(0, 0).0;
+            for (a, e) in actual.into_iter().zip(expected) {
+                assert!(a.abs_diff(e) <= 1, "{:?} != {:?}", actual, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn fast_path_matches_generic() {
+        let renderer = MaskRenderer::new();
+        let mask = renderer.generate_mask(get_test_mask(), 300, 200);
+        let cancel = CancellationToken::default();
+
+        let rgb = image::RgbImage::from_fn(300, 200, |x, y| {
+            image::Rgb([x as u8, y as u8, (x * y) as u8])
+        });
+        let mut generic = rgb.clone();
+        composite_mask(&mask, &mut generic, Compositing::default(), &cancel).unwrap();
+        let mut fast = rgb;
+        composite_mask_fast(&mask, &mut fast, &cancel).unwrap();
+        // the generic path truncates, while the fast one rounds
+        for (g, f) in generic.iter().zip(fast.iter()) {
+            assert!(g.abs_diff(*f) <= 1, "{} != {}", g, f);
+        }
+
+        let rgba = image::RgbaImage::from_fn(300, 200, |x, y| {
+            image::Rgba([x as u8, y as u8, (x * y) as u8, (x + y) as u8])
+        });
+        let mut generic = rgba.clone();
+        composite_mask(&mask, &mut generic, Compositing::default(), &cancel).unwrap();
+        let mut fast = rgba;
+        composite_mask_fast(&mask, &mut fast, &cancel).unwrap();
+        for (g, f) in generic.pixels().zip(fast.pixels()) {
+            assert!(g[3].abs_diff(f[3]) <= 1, "{:?} != {:?}", g, f);
+            // the color of a fully transparent pixel doesn't matter
+            if f[3] != 0 {
+                for (g, f) in g.0.iter().zip(f.0) {
+                    assert!(g.abs_diff(f) <= 1, "{:?} != {:?}", g, f);
+                }
+            }
+        }
+    }
+
     #[test]
     fn apply_mask_smoke() {
+        let renderer = MaskRenderer::new();
         for example_image in std::fs::read_dir("example_images").unwrap() {
             let example_image_entry = example_image.unwrap();
             println!("Applying to {}", example_image_entry.path().display());
             let mut example_image = image::open(example_image_entry.path()).unwrap().to_rgb8();
 
-            apply_mask(get_test_mask(), &mut example_image);
+            renderer
+                .apply_mask(
+                    get_test_mask(),
+                    &mut example_image,
+                    &CancellationToken::default(),
+                )
+                .unwrap();
 
             example_image
                 .save(format!(
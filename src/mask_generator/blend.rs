@@ -0,0 +1,124 @@
+//! Blend modes for compositing the mask over the image, as defined in the W3C Compositing and Blending spec
+
+use serde::Deserialize;
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Deserialize)]
+pub enum BlendMode {
+    /// Plain src-over
+    #[default]
+    Normal,
+    Multiply,
+    Screen,
+    Overlay,
+    SoftLight,
+    Difference,
+    /// Keeps the hue and the saturation of the image, taking the luminosity of the mask
+    Luminosity,
+}
+
+impl BlendMode {
+    /// Blends the (unpremultiplied) source color with the backdrop color, all the channels being in `0.0..=1.0`
+    pub fn blend(self, backdrop: [f32; 3], source: [f32; 3]) -> [f32; 3] {
+        let separable = |f: fn(f32, f32) -> f32| {
+            [
+                f(backdrop[0], source[0]),
+                f(backdrop[1], source[1]),
+                f(backdrop[2], source[2]),
+            ]
+        };
+
+        match self {
+            BlendMode::Normal => source,
+            BlendMode::Multiply => separable(|b, s| b * s),
+            BlendMode::Screen => separable(screen),
+            BlendMode::Overlay => separable(|b, s| hard_light(s, b)),
+            BlendMode::SoftLight => separable(soft_light),
+            BlendMode::Difference => separable(|b, s| (b - s).abs()),
+            BlendMode::Luminosity => set_luminosity(backdrop, luminosity(source)),
+        }
+    }
+}
+
+fn screen(b: f32, s: f32) -> f32 {
+    b + s - b * s
+}
+
+fn hard_light(b: f32, s: f32) -> f32 {
+    if s <= 0.5 {
+        b * 2.0 * s
+    } else {
+        screen(b, 2.0 * s - 1.0)
+    }
+}
+
+fn soft_light(b: f32, s: f32) -> f32 {
+    if s <= 0.5 {
+        b - (1.0 - 2.0 * s) * b * (1.0 - b)
+    } else {
+        let d = if b <= 0.25 {
+            ((16.0 * b - 12.0) * b + 4.0) * b
+        } else {
+            b.sqrt()
+        };
+        b + (2.0 * s - 1.0) * (d - b)
+    }
+}
+
+fn luminosity([r, g, b]: [f32; 3]) -> f32 {
+    0.3 * r + 0.59 * g + 0.11 * b
+}
+
+fn set_luminosity(color: [f32; 3], target: f32) -> [f32; 3] {
+    let delta = target - luminosity(color);
+    clip_color(color.map(|c| c + delta))
+}
+
+/// Brings the color back into the gamut, keeping its luminosity
+fn clip_color(color: [f32; 3]) -> [f32; 3] {
+    let l = luminosity(color);
+    let min = color[0].min(color[1]).min(color[2]);
+    let max = color[0].max(color[1]).max(color[2]);
+
+    if min < 0.0 {
+        color.map(|c| l + (c - l) * l / (l - min))
+    } else if max > 1.0 {
+        color.map(|c| l + (c - l) * (1.0 - l) / (max - l))
+    } else {
+        color
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BlendMode;
+
+    fn assert_close(actual: [f32; 3], expected: [f32; 3]) {
+        for (a, e) in actual.into_iter().zip(expected) {
+            assert!((a - e).abs() < 1e-5, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn blend_modes() {
+        let backdrop = [0.2, 0.5, 0.8];
+        let white = [1.0; 3];
+        let black = [0.0; 3];
+        let gray = [0.5; 3];
+
+        assert_close(BlendMode::Normal.blend(backdrop, gray), gray);
+        assert_close(BlendMode::Multiply.blend(backdrop, white), backdrop);
+        assert_close(BlendMode::Multiply.blend(backdrop, black), black);
+        assert_close(BlendMode::Screen.blend(backdrop, black), backdrop);
+        assert_close(BlendMode::Screen.blend(backdrop, white), white);
+        // neutral gray doesn't change the image with the contrast modes
+        assert_close(BlendMode::Overlay.blend(backdrop, gray), backdrop);
+        assert_close(BlendMode::SoftLight.blend(backdrop, gray), backdrop);
+        assert_close(
+            BlendMode::Difference.blend(backdrop, white),
+            [0.8, 0.5, 0.2],
+        );
+        assert_close(BlendMode::Difference.blend(backdrop, backdrop), black);
+        assert_close(BlendMode::Luminosity.blend(backdrop, white), white);
+        assert_close(BlendMode::Luminosity.blend(gray, backdrop), [0.443; 3]);
+    }
+}
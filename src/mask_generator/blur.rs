@@ -0,0 +1,96 @@
+//! Approximate gaussian blur, used for the text shadow
+
+use tiny_skia::Pixmap;
+
+/// Number of box blurs used to approximate the gaussian
+const PASSES: usize = 3;
+
+/// Blurs the premultiplied pixmap with a gaussian of the standard deviation
+///
+/// The gaussian is approximated by a few box blurs, the pixels outside of the pixmap are treated as transparent
+pub fn gaussian_blur(pixmap: &mut Pixmap, sigma: f32) {
+    // the box size that gives the same variance after all the passes
+    let box_size = (12.0 * sigma * sigma / PASSES as f32 + 1.0).sqrt();
+    let radius = ((box_size - 1.0) / 2.0).round() as usize;
+    if radius == 0 {
+        return;
+    }
+
+    let (width, height) = (pixmap.width() as usize, pixmap.height() as usize);
+    let data = pixmap.data_mut();
+    let mut line = Vec::new();
+    for _ in 0..PASSES {
+        for y in 0..height {
+            blur_line(data, y * width * 4, 4, width, radius, &mut line);
+        }
+        for x in 0..width {
+            blur_line(data, x * 4, width * 4, height, radius, &mut line);
+        }
+    }
+}
+
+/// Box blurs `len` pixels starting at `start` and `stride` bytes apart
+fn blur_line(
+    data: &mut [u8],
+    start: usize,
+    stride: usize,
+    len: usize,
+    radius: usize,
+    line: &mut Vec<[u32; 4]>,
+) {
+    line.clear();
+    line.extend((0..len).map(|i| {
+        let offset = start + i * stride;
+        [0, 1, 2, 3].map(|c| data[offset + c] as u32)
+    }));
+
+    let size = 2 * radius as u32 + 1;
+    let mut sum = [0u32; 4];
+    for pixel in line.iter().take(radius) {
+        (0..4).for_each(|c| sum[c] += pixel[c]);
+    }
+
+    for i in 0..len {
+        // the window is `i - radius..=i + radius`
+        if let Some(entering) = line.get(i + radius) {
+            (0..4).for_each(|c| sum[c] += entering[c]);
+        }
+        if i > radius {
+            let leaving = line[i - radius - 1];
+            (0..4).for_each(|c| sum[c] -= leaving[c]);
+        }
+
+        let offset = start + i * stride;
+        for c in 0..4 {
+            data[offset + c] = ((sum[c] + size / 2) / size) as u8;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::gaussian_blur;
+
+    #[test]
+    fn blur_square() {
+        let mut pixmap = tiny_skia::Pixmap::new(40, 40).unwrap();
+        let square = tiny_skia::Rect::from_xywh(10.0, 10.0, 20.0, 20.0).unwrap();
+        let mut paint = tiny_skia::Paint::default();
+        paint.set_color_rgba8(255, 255, 255, 255);
+        pixmap.fill_rect(square, &paint, tiny_skia::Transform::identity(), None);
+
+        gaussian_blur(&mut pixmap, 2.0);
+
+        let alpha = |x, y| pixmap.pixel(x, y).unwrap().alpha();
+        // the middle of the square is too far from the edges to change
+        assert_eq!(alpha(20, 20), 255);
+        // the edges are smoothed both ways
+        assert!(alpha(10, 20) > 64 && alpha(10, 20) < 192);
+        assert!(alpha(8, 20) > 0);
+        assert!(alpha(11, 20) > alpha(9, 20));
+        assert_eq!(alpha(0, 0), 0);
+        // the blur is symmetric
+        assert_eq!(alpha(10, 20), alpha(29, 20));
+        assert_eq!(alpha(20, 10), alpha(20, 29));
+    }
+}
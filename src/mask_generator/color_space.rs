@@ -0,0 +1,97 @@
+//! Conversions between the sRGB-encoded and the linear light values, used for gamma-correct compositing
+
+use serde::Deserialize;
+use std::sync::LazyLock;
+
+/// The color space the mask is composited in
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Deserialize)]
+pub enum ColorSpace {
+    /// Blend the sRGB-encoded values directly, like most image libraries do
+    #[default]
+    Srgb,
+    /// Decode the values to linear light before blending, like the design tools do.
+    /// Semi-transparent light colors look lighter and more even this way
+    Linear,
+}
+
+/// Number of the points the conversions are tabulated at
+///
+/// The values between them are interpolated, so the 16-bit images and the unpremultiplied colors
+/// don't get rounded to the 8-bit steps, which would show up as banding in the smooth gradients
+const TABLE_SIZE: usize = 4096;
+
+static DECODE_TABLE: LazyLock<Vec<f32>> = LazyLock::new(|| tabulate(srgb_to_linear_exact));
+
+/// Indexed by the square root of the linear value, as the encoding is too steep near black for even steps
+static ENCODE_TABLE: LazyLock<Vec<f32>> =
+    LazyLock::new(|| tabulate(|v| linear_to_srgb_exact(v * v)));
+
+fn tabulate(f: fn(f32) -> f32) -> Vec<f32> {
+    (0..TABLE_SIZE)
+        .map(|i| f(i as f32 / (TABLE_SIZE - 1) as f32))
+        .collect()
+}
+
+/// Looks up a position in `0.0..=1.0`, interpolating linearly between the neighbouring points
+fn lookup(table: &[f32], position: f32) -> f32 {
+    let position = position * (TABLE_SIZE - 1) as f32;
+    let i = (position as usize).min(TABLE_SIZE - 2);
+    let t = position - i as f32;
+    table[i] + (table[i + 1] - table[i]) * t
+}
+
+fn srgb_to_linear_exact(v: f32) -> f32 {
+    if v <= 0.04045 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb_exact(v: f32) -> f32 {
+    if v <= 0.0031308 {
+        v * 12.92
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Decodes an sRGB value in `0.0..=1.0`
+pub fn srgb_to_linear(v: f32) -> f32 {
+    lookup(&DECODE_TABLE, v.clamp(0.0, 1.0))
+}
+
+/// Encodes a linear value in `0.0..=1.0` back to sRGB
+pub fn linear_to_srgb(v: f32) -> f32 {
+    lookup(&ENCODE_TABLE, v.clamp(0.0, 1.0).sqrt())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{linear_to_srgb, linear_to_srgb_exact, srgb_to_linear, srgb_to_linear_exact};
+
+    #[test]
+    fn tables_match_exact_conversion() {
+        for i in 0..=255u8 {
+            let v = i as f32 / 255.0;
+            assert!((srgb_to_linear(v) - srgb_to_linear_exact(v)).abs() < 1e-6);
+            // all the 8-bit values survive the round trip
+            assert_eq!((linear_to_srgb(srgb_to_linear(v)) * 255.0).round() as u8, i);
+        }
+        for i in 0..=1000 {
+            let v = i as f32 / 1000.0;
+            assert!((linear_to_srgb(v) - linear_to_srgb_exact(v)).abs() < 0.5 / 255.0);
+        }
+    }
+
+    #[test]
+    fn keeps_16_bit_precision() {
+        // a step of a 16-bit gradient, way finer than the 8-bit one
+        for i in (0..=u16::MAX).step_by(7) {
+            let v = i as f32 / u16::MAX as f32;
+            let decoded = srgb_to_linear(v);
+            assert!((decoded - srgb_to_linear_exact(v)).abs() < 0.1 / u16::MAX as f32);
+            assert!((linear_to_srgb(decoded) - v).abs() < 0.5 / u16::MAX as f32);
+        }
+    }
+}
@@ -0,0 +1,97 @@
+//! Fast path of the compositing for 8-bit RGB and RGBA buffers
+//!
+//! Does plain src-over in integers on contiguous rows, with the rows processed in parallel.
+//! Watermark masks are mostly transparent, so the fully transparent spans of the mask are skipped.
+
+use crate::cancellation::{CancellationToken, Cancelled};
+use image::{ImageBuffer, Pixel, Rgb, Rgba};
+use rayon::prelude::*;
+
+/// Number of pixels checked at once when skipping the transparent parts of the mask
+const SPAN: usize = 16;
+
+/// Pixel formats with a fast compositing path
+pub trait FastPixel: Pixel<Subpixel = u8> + Send + Sync {
+    /// Composites a premultiplied mask pixel over the pixel channels
+    fn composite(pixel: &mut [u8], mask: [u8; 4]);
+}
+
+/// `x / 255`, rounded, exact for `x <= 255 * 255`
+pub(super) fn div255(x: u32) -> u32 {
+    let x = x + 128;
+    (x + (x >> 8)) >> 8
+}
+
+impl FastPixel for Rgb<u8> {
+    fn composite(pixel: &mut [u8], [r, g, b, a]: [u8; 4]) {
+        let inverse = 255 - a as u32;
+        for (channel, mask) in pixel.iter_mut().zip([r, g, b]) {
+            // the mask is premultiplied, so this never goes over 255
+            *channel = (mask as u32 + div255(*channel as u32 * inverse)).min(255) as u8;
+        }
+    }
+}
+
+impl FastPixel for Rgba<u8> {
+    fn composite(pixel: &mut [u8], [r, g, b, a]: [u8; 4]) {
+        let inverse = 255 - a as u32;
+        let bg_a = pixel[3] as u32;
+        // scaled by 255 * 255, so that the colors are unpremultiplied without losing precision
+        let out_a = a as u32 * 255 + bg_a * inverse;
+        if out_a == 0 {
+            pixel.copy_from_slice(&[0; 4]);
+            return;
+        }
+
+        for (channel, mask) in pixel[..3].iter_mut().zip([r, g, b]) {
+            let out_premultiplied = mask as u32 * 255 * 255 + *channel as u32 * bg_a * inverse;
+            *channel = ((out_premultiplied + out_a / 2) / out_a).min(255) as u8;
+        }
+        pixel[3] = div255(out_a) as u8;
+    }
+}
+
+/// Composites a premultiplied RGBA mask over the image using src-over
+pub fn composite_mask_fast<P: FastPixel>(
+    mask: &image::RgbaImage,
+    image: &mut ImageBuffer<P, Vec<u8>>,
+    cancel: &CancellationToken,
+) -> Result<(), Cancelled> {
+    assert_eq!(image.width(), mask.width());
+    assert_eq!(image.height(), mask.height());
+
+    let channels = P::CHANNEL_COUNT as usize;
+    let width = image.width() as usize;
+    if width == 0 {
+        return Ok(());
+    }
+
+    image
+        .par_chunks_exact_mut(width * channels)
+        .zip(mask.par_chunks_exact(width * 4))
+        .try_for_each(|(image_row, mask_row)| {
+            cancel.check()?;
+
+            for (image_span, mask_span) in image_row
+                .chunks_mut(SPAN * channels)
+                .zip(mask_row.chunks(SPAN * 4))
+            {
+                if mask_span.iter().all(|&v| v == 0) {
+                    continue;
+                }
+
+                for (pixel, mask_pixel) in image_span
+                    .chunks_exact_mut(channels)
+                    .zip(mask_span.chunks_exact(4))
+                {
+                    let mask_pixel: [u8; 4] = mask_pixel.try_into().unwrap();
+                    if mask_pixel[3] == 0 {
+                        continue;
+                    }
+                    P::composite(pixel, mask_pixel);
+                }
+            }
+
+            Ok(())
+        })
+}
@@ -0,0 +1,164 @@
+//! Seeded random changes of the copies of the text, so that the grid is not perfectly regular
+
+/// Number of differently rotated and scaled renders of the text
+///
+/// Each copy picks one of them, so that the text is rendered a fixed number of times instead of for every copy.
+/// The copies only get this many distinct rotations and sizes then, so it's large enough for the repeats
+/// not to stand out among the copies of a mask, while the renders still take less time than the blending
+const VARIANT_COUNT: u64 = 32;
+
+/// Keep the random values of the variants and of the copies independent
+const VARIANT_KEY: u64 = 1;
+const INSTANCE_KEY: u64 = 2;
+
+/// The jitter of a mask, with the sizes resolved for a particular mask size
+#[derive(Debug, Clone)]
+pub struct Jitter {
+    pub seed: u64,
+    /// The most a copy is moved by in each direction, in pixels
+    pub offset: f32,
+    /// The most a copy is rotated by, in degrees
+    pub rotation: f32,
+    /// The most the size of a copy is changed by, in `0.0..1.0` so that no copy shrinks to nothing
+    pub scale: f32,
+    pub opacity: f32,
+}
+
+/// A transformation of the text, shared by some of the copies
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Variant {
+    /// Added to the rotation of the text, in degrees
+    pub rotation: f32,
+    pub scale: f32,
+}
+
+impl Default for Variant {
+    fn default() -> Self {
+        Self {
+            rotation: 0.0,
+            scale: 1.0,
+        }
+    }
+}
+
+/// A single copy of the text
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Instance {
+    pub x: i32,
+    pub y: i32,
+    /// Index of the variant the copy is drawn with
+    pub variant: usize,
+    pub opacity: u8,
+}
+
+impl Jitter {
+    pub fn variants(&self) -> Vec<Variant> {
+        if self.rotation == 0.0 && self.scale == 0.0 {
+            return vec![Variant::default()];
+        }
+
+        (0..VARIANT_COUNT)
+            .map(|index| {
+                let mut rng = Rng::new(&[self.seed, VARIANT_KEY, index]);
+                Variant {
+                    rotation: rng.symmetric(self.rotation),
+                    scale: 1.0 + rng.symmetric(self.scale),
+                }
+            })
+            .collect()
+    }
+
+    /// Moves the copy at the grid position and picks its variant and opacity
+    ///
+    /// The values only depend on the seed and the grid position, not on the order the copies are placed in
+    pub fn instance(&self, x: i32, y: i32, variant_count: usize) -> Instance {
+        let mut rng = Rng::new(&[self.seed, INSTANCE_KEY, x as u64, y as u64]);
+        let dx = rng.symmetric(self.offset).round() as i32;
+        let dy = rng.symmetric(self.offset).round() as i32;
+        let variant = (rng.next_u64() % variant_count as u64) as usize;
+        let opacity = 1.0 - rng.next_f32() * self.opacity.clamp(0.0, 1.0);
+
+        Instance {
+            x: x + dx,
+            y: y + dy,
+            variant,
+            opacity: (opacity * 255.0).round() as u8,
+        }
+    }
+}
+
+/// SplitMix64, so that the same seed gives the same mask regardless of the versions of the dependencies
+struct Rng(u64);
+
+impl Rng {
+    fn new(keys: &[u64]) -> Self {
+        Self(keys.iter().fold(0, |state, &key| mix(state ^ key)))
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        mix(self.0)
+    }
+
+    /// A value in `0.0..1.0`
+    fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    /// A value in `-max..max`
+    fn symmetric(&mut self, max: f32) -> f32 {
+        (self.next_f32() * 2.0 - 1.0) * max
+    }
+}
+
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Jitter, Variant, VARIANT_COUNT};
+
+    #[test]
+    fn jitter_is_reproducible() {
+        let jitter = |seed| Jitter {
+            seed,
+            offset: 10.0,
+            rotation: 15.0,
+            scale: 0.2,
+            opacity: 0.5,
+        };
+        let instances = |jitter: &Jitter| {
+            (0..20)
+                .map(|i| jitter.instance(i * 100, -i * 50, 8))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(jitter(1).variants(), jitter(1).variants());
+        assert_eq!(instances(&jitter(1)), instances(&jitter(1)));
+        assert_ne!(instances(&jitter(1)), instances(&jitter(2)));
+
+        let variants = jitter(1).variants();
+        assert_eq!(variants.len(), VARIANT_COUNT as usize);
+        for variant in variants {
+            assert!(variant.rotation.abs() <= 15.0);
+            assert!((0.8..=1.2).contains(&variant.scale));
+        }
+        for (i, instance) in (0..).zip(instances(&jitter(1))) {
+            assert!((instance.x - i * 100).abs() <= 10);
+            assert!((instance.y + i * 50).abs() <= 10);
+            assert!(instance.variant < 8);
+            assert!(instance.opacity >= 127);
+        }
+
+        // nothing to vary, so there's a single variant
+        let offset_only = Jitter {
+            rotation: 0.0,
+            scale: 0.0,
+            ..jitter(1)
+        };
+        assert_eq!(offset_only.variants(), [Variant::default()]);
+    }
+}
@@ -0,0 +1,148 @@
+//! Lengths in the mask config, either absolute or relative to the size of the image
+
+use serde::Deserialize;
+use std::fmt;
+use std::str::FromStr;
+
+/// What a [`Length`] is measured in
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LengthUnit {
+    Px,
+    /// Percent of the longer side of the image
+    Long,
+    /// Percent of the shorter side of the image
+    Short,
+    Width,
+    Height,
+    /// Percent of the diagonal of the image
+    Diagonal,
+}
+
+impl LengthUnit {
+    const SUFFIXES: [(&'static str, LengthUnit); 6] = [
+        ("px", LengthUnit::Px),
+        ("%long", LengthUnit::Long),
+        ("%short", LengthUnit::Short),
+        ("%width", LengthUnit::Width),
+        ("%height", LengthUnit::Height),
+        ("%diagonal", LengthUnit::Diagonal),
+    ];
+}
+
+/// A length written like `40px` or `5%short`
+///
+/// A plain number is in percent of the longer side, which is how all the sizes were specified before the units
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Length {
+    pub value: f32,
+    pub unit: LengthUnit,
+}
+
+impl Length {
+    /// Returns the length in pixels for an image of the given size
+    pub fn resolve(&self, width: u32, height: u32) -> f32 {
+        let (width, height) = (width as f32, height as f32);
+        let side = match self.unit {
+            LengthUnit::Px => return self.value,
+            LengthUnit::Long => width.max(height),
+            LengthUnit::Short => width.min(height),
+            LengthUnit::Width => width,
+            LengthUnit::Height => height,
+            LengthUnit::Diagonal => width.hypot(height),
+        };
+        side * self.value / 100.0
+    }
+}
+
+impl FromStr for Length {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (value, unit) = LengthUnit::SUFFIXES
+            .iter()
+            .find_map(|&(suffix, unit)| Some((s.strip_suffix(suffix)?, unit)))
+            .unwrap_or((s, LengthUnit::Long));
+
+        let value = value.trim().parse().map_err(|_| {
+            format!(
+                "invalid length `{}`, expected a number followed by one of \
+                px, %long, %short, %width, %height, %diagonal",
+                s
+            )
+        })?;
+
+        Ok(Length { value, unit })
+    }
+}
+
+impl<'de> Deserialize<'de> for Length {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct LengthVisitor;
+
+        impl serde::de::Visitor<'_> for LengthVisitor {
+            type Value = Length;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a number or a string like `40px` or `5%short`")
+            }
+
+            fn visit_f64<E: serde::de::Error>(self, value: f64) -> Result<Length, E> {
+                Ok(Length {
+                    value: value as f32,
+                    unit: LengthUnit::Long,
+                })
+            }
+
+            fn visit_i64<E: serde::de::Error>(self, value: i64) -> Result<Length, E> {
+                self.visit_f64(value as f64)
+            }
+
+            fn visit_u64<E: serde::de::Error>(self, value: u64) -> Result<Length, E> {
+                self.visit_f64(value as f64)
+            }
+
+            fn visit_str<E: serde::de::Error>(self, value: &str) -> Result<Length, E> {
+                value.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_any(LengthVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Length, LengthUnit};
+
+    #[test]
+    fn parse_and_resolve() {
+        let resolve = |s: &str| s.parse::<Length>().unwrap().resolve(300, 400);
+
+        assert_eq!(resolve("40px"), 40.0);
+        assert_eq!(resolve("-30%long"), -120.0);
+        assert_eq!(resolve("10%short"), 30.0);
+        assert_eq!(resolve("10%width"), 30.0);
+        assert_eq!(resolve("10%height"), 40.0);
+        assert_eq!(resolve("10%diagonal"), 50.0);
+        // no unit is the percent of the longer side
+        assert_eq!(resolve(" 5 "), 20.0);
+
+        assert!("5%".parse::<Length>().is_err());
+        assert!("px".parse::<Length>().is_err());
+
+        let length: Length = serde_json::from_value(serde_json::json!(2)).unwrap();
+        assert_eq!(
+            length,
+            Length {
+                value: 2.0,
+                unit: LengthUnit::Long
+            }
+        );
+        let length: Length = serde_json::from_value(serde_json::json!("2.5px")).unwrap();
+        assert_eq!(length.resolve(1, 1), 2.5);
+    }
+}
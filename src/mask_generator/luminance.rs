@@ -0,0 +1,91 @@
+//! Luminance of the image under the mask, used to pick the text color that stands out
+
+use super::sprite::Sprite;
+use image::{GenericImageView, Pixel, Primitive};
+
+/// Distance between the sampled pixels of a sprite, in both directions
+const SAMPLE_STEP: usize = 4;
+
+/// Luma of the image pixels, in `0..=255`
+pub struct Luminance {
+    width: u32,
+    height: u32,
+    values: Vec<u8>,
+}
+
+impl Luminance {
+    pub fn new<I, P, T>(image: &I) -> Self
+    where
+        I: GenericImageView<Pixel = P>,
+        P: Pixel<Subpixel = T>,
+        T: Primitive,
+    {
+        let max = T::DEFAULT_MAX_VALUE.to_f32().unwrap();
+        let values = image
+            .pixels()
+            .map(|(_, _, pixel)| {
+                let [r, g, b, _] = pixel.to_rgba().0.map(|c| c.to_f32().unwrap() / max);
+                (luma([r, g, b]) * 255.0).round() as u8
+            })
+            .collect();
+
+        Self {
+            width: image.width(),
+            height: image.height(),
+            values,
+        }
+    }
+
+    /// Average luminance under the visible pixels of the sprite drawn at the position, in `0.0..=1.0`
+    ///
+    /// Returns `None` if the sprite is completely outside of the image
+    pub fn under(&self, sprite: &Sprite, x: i32, y: i32) -> Option<f32> {
+        let (mut sum, mut count) = (0u64, 0u64);
+        for (sprite_x, sprite_y) in sprite.covered_pixels(SAMPLE_STEP) {
+            let (x, y) = (x as i64 + sprite_x, y as i64 + sprite_y);
+            if x < 0 || y < 0 || x >= self.width as i64 || y >= self.height as i64 {
+                continue;
+            }
+            sum += self.values[(y * self.width as i64 + x) as usize] as u64;
+            count += 1;
+        }
+
+        (count != 0).then(|| sum as f32 / count as f32 / 255.0)
+    }
+}
+
+/// Rec. 709 luma of a gamma-encoded color
+pub fn luma([r, g, b]: [f32; 3]) -> f32 {
+    0.2126 * r + 0.7152 * g + 0.0722 * b
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Luminance;
+    use crate::mask_generator::sprite::Sprite;
+
+    #[test]
+    fn luminance_under_sprite() {
+        // the left half is black, the right one is white
+        let image = image::RgbImage::from_fn(100, 100, |x, _| {
+            if x < 50 {
+                image::Rgb([0, 0, 0])
+            } else {
+                image::Rgb([255, 255, 255])
+            }
+        });
+        let luminance = Luminance::new(&image);
+
+        let mut sprite = tiny_skia::Pixmap::new(20, 20).unwrap();
+        sprite.fill(tiny_skia::Color::WHITE);
+        let sprite = Sprite::new(sprite, 255);
+
+        assert_eq!(luminance.under(&sprite, 10, 10), Some(0.0));
+        assert_eq!(luminance.under(&sprite, 70, 10), Some(1.0));
+        let middle = luminance.under(&sprite, 40, 10).unwrap();
+        assert!(middle > 0.3 && middle < 0.7);
+        // only the part inside of the image counts
+        assert_eq!(luminance.under(&sprite, 90, -10), Some(1.0));
+        assert_eq!(luminance.under(&sprite, 200, 200), None);
+    }
+}
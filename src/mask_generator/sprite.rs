@@ -0,0 +1,171 @@
+//! Stamping of a pre-rendered sprite onto the mask
+
+use super::fast::div255;
+use rayon::prelude::*;
+use std::ops::Range;
+
+/// Number of rows of the pixmap drawn by one task
+const BAND_HEIGHT: usize = 64;
+
+/// Where a sprite is drawn, and how opaque
+#[derive(Debug, Copy, Clone)]
+pub struct Stamp {
+    pub x: i32,
+    pub y: i32,
+    /// Multiplies the alpha the sprite was made with
+    pub opacity: u8,
+}
+
+/// A premultiplied image that is drawn many times
+pub struct Sprite {
+    pixmap: tiny_skia::Pixmap,
+    /// Columns between the first and the last non-transparent pixel of each row
+    ///
+    /// A rotated line of text covers only a narrow band of its bounding box, so most of it can be skipped
+    rows: Vec<Range<i64>>,
+}
+
+impl Sprite {
+    /// Makes a sprite from the pixmap, applying the alpha to the whole of it
+    pub fn new(mut pixmap: tiny_skia::Pixmap, alpha: u8) -> Self {
+        if alpha != 255 {
+            for value in pixmap.data_mut() {
+                *value = div255(*value as u32 * alpha as u32) as u8;
+            }
+        }
+
+        let width = pixmap.width() as usize;
+        let rows = pixmap
+            .pixels()
+            .chunks_exact(width)
+            .map(|row| {
+                let start = row.iter().position(|p| p.alpha() != 0);
+                let end = row.iter().rposition(|p| p.alpha() != 0);
+                match (start, end) {
+                    (Some(start), Some(end)) => start as i64..end as i64 + 1,
+                    _ => 0..0,
+                }
+            })
+            .collect();
+
+        Self { pixmap, rows }
+    }
+
+    /// Positions of the non-transparent pixels, only looking at every `step`-th row and column
+    pub fn covered_pixels(&self, step: usize) -> impl Iterator<Item = (i64, i64)> + '_ {
+        let width = self.pixmap.width() as i64;
+        let pixels = self.pixmap.pixels();
+        (0i64..)
+            .zip(&self.rows)
+            .step_by(step)
+            .flat_map(move |(y, columns)| {
+                columns
+                    .clone()
+                    .step_by(step)
+                    .filter(move |&x| pixels[(y * width + x) as usize].alpha() != 0)
+                    .map(move |x| (x, y))
+            })
+    }
+
+    /// Draws the sprite at every stamp over the premultiplied pixmap using src-over
+    ///
+    /// The parts of the sprite outside of the pixmap are clipped.
+    /// This is a lot cheaper than `Pixmap::draw_pixmap`, which goes through the whole rendering pipeline,
+    /// and the pixmap is split into bands of rows which are drawn in parallel.
+    pub fn stamp(&self, pixmap: &mut tiny_skia::Pixmap, stamps: &[Stamp]) {
+        let width = pixmap.width() as usize;
+        if width == 0 {
+            return;
+        }
+
+        pixmap
+            .data_mut()
+            .par_chunks_mut(width * 4 * BAND_HEIGHT)
+            .enumerate()
+            .for_each(|(band_index, band)| {
+                let top = (band_index * BAND_HEIGHT) as i64;
+                for stamp in stamps {
+                    self.stamp_band(band, width as i64, top, stamp);
+                }
+            });
+    }
+
+    /// Draws the part of the sprite that falls into a band of rows starting at `top`
+    fn stamp_band(&self, band: &mut [u8], width: i64, top: i64, stamp: &Stamp) {
+        let (x, y) = (stamp.x as i64, stamp.y as i64);
+        let opacity = stamp.opacity as u32;
+        let height = band.len() as i64 / 4 / width;
+        let sprite_width = self.pixmap.width() as i64;
+
+        let first = (top - y).max(0);
+        let last = (top + height - y).min(self.rows.len() as i64);
+        for sprite_row in first..last {
+            let columns = &self.rows[sprite_row as usize];
+            let left = (x + columns.start).max(0);
+            let right = (x + columns.end).min(width);
+            if left >= right {
+                continue;
+            }
+
+            let row = y + sprite_row - top;
+            let row_len = (right - left) as usize * 4;
+            let dst_start = (row * width + left) as usize * 4;
+            let src_start = (sprite_row * sprite_width + (left - x)) as usize * 4;
+            let dst = &mut band[dst_start..dst_start + row_len];
+            let src = &self.pixmap.data()[src_start..src_start + row_len];
+
+            for (dst, src) in dst.chunks_exact_mut(4).zip(src.chunks_exact(4)) {
+                if src[3] == 0 {
+                    continue;
+                }
+                let src = if opacity == 255 {
+                    [src[0], src[1], src[2], src[3]].map(|c| c as u32)
+                } else {
+                    [src[0], src[1], src[2], src[3]].map(|c| div255(c as u32 * opacity))
+                };
+                let inverse = 255 - src[3];
+                for (dst, src) in dst.iter_mut().zip(src) {
+                    *dst = (src + div255(*dst as u32 * inverse)).min(255) as u8;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Sprite, Stamp};
+
+    #[test]
+    fn stamp_clipped() {
+        let mut pixmap = tiny_skia::Pixmap::new(4, 4).unwrap();
+        let mut sprite = tiny_skia::Pixmap::new(2, 2).unwrap();
+        sprite.fill(tiny_skia::Color::from_rgba8(255, 255, 255, 128));
+        let sprite = Sprite::new(sprite, 255);
+
+        // only the bottom right pixel of the first stamp is inside, and only the top left one of the second
+        let stamp = |x, y| Stamp { x, y, opacity: 255 };
+        sprite.stamp(&mut pixmap, &[stamp(-1, -1), stamp(3, 3), stamp(10, 10)]);
+
+        let alphas = pixmap
+            .pixels()
+            .iter()
+            .map(|p| p.alpha())
+            .collect::<Vec<_>>();
+        assert_eq!(alphas, [128, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 128]);
+
+        // src-over of two half-transparent pixels
+        sprite.stamp(&mut pixmap, &[stamp(0, 0)]);
+        assert_eq!(pixmap.pixel(0, 0).unwrap().alpha(), 192);
+
+        // and with the opacity halved
+        sprite.stamp(
+            &mut pixmap,
+            &[Stamp {
+                opacity: 128,
+                ..stamp(3, 0)
+            }],
+        );
+        assert_eq!(pixmap.pixel(3, 0).unwrap().alpha(), 64);
+    }
+}
@@ -0,0 +1,385 @@
+//! Layout of the mask text into outlines
+//!
+//! The text is shaped and turned into paths directly, so it never has to be put into an SVG document.
+
+use rustybuzz::ttf_parser;
+use serde::Deserialize;
+use tiny_skia::{LineJoin, Path, PathBuilder, PathSegment, Point, Stroke, Transform};
+
+/// Slant of the synthesized italic, like the one browsers use
+const SYNTHETIC_ITALIC_ANGLE: f32 = 12.0;
+
+/// Collects the glyph outlines into a path, converting from the font units
+struct Outline {
+    builder: PathBuilder,
+    scale: f32,
+    /// Horizontal shift per unit of height, to slant the glyphs
+    skew: f32,
+    /// Origin of the current glyph
+    x: f32,
+    y: f32,
+}
+
+impl Outline {
+    fn point(&self, x: f32, y: f32) -> (f32, f32) {
+        // the font y axis goes up, the image one goes down
+        (
+            self.x + (x + y * self.skew) * self.scale,
+            self.y - y * self.scale,
+        )
+    }
+}
+
+impl ttf_parser::OutlineBuilder for Outline {
+    fn move_to(&mut self, x: f32, y: f32) {
+        let (x, y) = self.point(x, y);
+        self.builder.move_to(x, y);
+    }
+
+    fn line_to(&mut self, x: f32, y: f32) {
+        let (x, y) = self.point(x, y);
+        self.builder.line_to(x, y);
+    }
+
+    fn quad_to(&mut self, x1: f32, y1: f32, x: f32, y: f32) {
+        let (x1, y1) = self.point(x1, y1);
+        let (x, y) = self.point(x, y);
+        self.builder.quad_to(x1, y1, x, y);
+    }
+
+    fn curve_to(&mut self, x1: f32, y1: f32, x2: f32, y2: f32, x: f32, y: f32) {
+        let (x1, y1) = self.point(x1, y1);
+        let (x2, y2) = self.point(x2, y2);
+        let (x, y) = self.point(x, y);
+        self.builder.cubic_to(x1, y1, x2, y2, x, y);
+    }
+
+    fn close(&mut self) {
+        self.builder.close();
+    }
+}
+
+/// Whether the glyphs are upright or slanted
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Deserialize)]
+pub enum FontStyle {
+    #[default]
+    Normal,
+    Italic,
+    Oblique,
+}
+
+/// How the lines of the text are aligned to its position
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Deserialize)]
+pub enum TextAlign {
+    /// The lines start at the position
+    #[default]
+    Left,
+    Center,
+    /// The lines end at the position
+    Right,
+}
+
+/// Everything that affects the layout of the text, with the sizes in pixels
+#[derive(Debug, Clone)]
+pub struct TextStyle {
+    pub family: String,
+    pub weight: u16,
+    pub style: FontStyle,
+    pub size: f32,
+    /// Extra space between the characters
+    pub letter_spacing: f32,
+    /// Distance between the baselines of the lines, relative to the font size
+    pub line_height: f32,
+    pub align: TextAlign,
+}
+
+impl TextStyle {
+    /// Finds the face that matches the style best
+    ///
+    /// Falls back to any loaded font if the family is not available.
+    /// The weight and the slant the face lacks are synthesized then, see [`TextStyle::synthesis`]
+    fn face(&self, fontdb: &fontdb::Database) -> Option<fontdb::ID> {
+        fontdb
+            .query(&fontdb::Query {
+                families: &[fontdb::Family::Name(&self.family)],
+                weight: fontdb::Weight(self.weight),
+                style: match self.style {
+                    FontStyle::Normal => fontdb::Style::Normal,
+                    FontStyle::Italic => fontdb::Style::Italic,
+                    FontStyle::Oblique => fontdb::Style::Oblique,
+                },
+                ..Default::default()
+            })
+            .or_else(|| fontdb.faces().next().map(|face| face.id))
+    }
+
+    /// Returns how much to embolden the face by, as a stroke width in pixels, and how much to slant it by
+    fn synthesis(&self, face: &fontdb::FaceInfo) -> (f32, f32) {
+        // 700 over a regular face gets a stroke of 1/24 of the size, like in browsers
+        let extra_weight = self.weight.saturating_sub(face.weight.0) as f32;
+        let embolden = self.size / 24.0 * extra_weight / 300.0;
+
+        let skew = match (self.style, face.style) {
+            (FontStyle::Italic | FontStyle::Oblique, fontdb::Style::Normal) => {
+                SYNTHETIC_ITALIC_ANGLE.to_radians().tan()
+            }
+            _ => 0.0,
+        };
+
+        (embolden, skew)
+    }
+}
+
+/// Thickens the glyphs by adding the stroke of their outline
+fn embolden(path: Path, width: f32) -> Option<Path> {
+    let stroke = |path: &Path| {
+        path.stroke(
+            &Stroke {
+                width,
+                line_join: LineJoin::Round,
+                ..Default::default()
+            },
+            1.0,
+        )
+    };
+
+    // the stroke winds the same way whatever the direction of the outline, so it would cancel out
+    // the glyphs that wind the other way. Stroking the mirrored outline and mirroring it back flips it
+    let mut stroke_path = stroke(&path)?;
+    if signed_area(&stroke_path).is_sign_positive() != signed_area(&path).is_sign_positive() {
+        let mirror = Transform::from_scale(-1.0, 1.0);
+        stroke_path = stroke(&path.clone().transform(mirror)?)?.transform(mirror)?;
+    }
+
+    let mut builder = PathBuilder::new();
+    builder.push_path(&path);
+    builder.push_path(&stroke_path);
+    builder.finish()
+}
+
+/// Area of the path, with the sign telling which way its filled parts wind
+///
+/// The curves are approximated by their control points, which is enough to tell the direction
+fn signed_area(path: &Path) -> f32 {
+    let mut area = 0.0;
+    let mut start = Point::zero();
+    let mut last = Point::zero();
+    let mut add = |from: Point, to: Point| area += from.x * to.y - to.x * from.y;
+
+    for segment in path.segments() {
+        let points = match segment {
+            PathSegment::MoveTo(p) => {
+                add(last, start);
+                (start, last) = (p, p);
+                continue;
+            }
+            PathSegment::LineTo(p) => vec![p],
+            PathSegment::QuadTo(p1, p) => vec![p1, p],
+            PathSegment::CubicTo(p1, p2, p) => vec![p1, p2, p],
+            PathSegment::Close => vec![start],
+        };
+        for p in points {
+            add(last, p);
+            last = p;
+        }
+    }
+    add(last, start);
+
+    area / 2.0
+}
+
+/// Shapes the text and returns its outline, with the start of the first baseline at the origin
+///
+/// The text is split into lines on `\n`, each line is aligned to the origin according to the style.
+/// Returns `None` if there is nothing to draw, like for a whitespace only text
+pub fn outline(fontdb: &fontdb::Database, text: &str, style: &TextStyle) -> Option<Path> {
+    let id = style.face(fontdb)?;
+    let (emboldening, skew) = style.synthesis(fontdb.face(id)?);
+
+    let path = fontdb
+        .with_face_data(id, |data, index| {
+            let face = rustybuzz::Face::from_slice(data, index)?;
+            let scale = style.size / face.units_per_em() as f32;
+
+            let mut outline = Outline {
+                builder: PathBuilder::new(),
+                scale,
+                skew,
+                x: 0.0,
+                y: 0.0,
+            };
+            for (line_index, line) in text.split('\n').enumerate() {
+                let mut buffer = rustybuzz::UnicodeBuffer::new();
+                buffer.push_str(line.trim_end_matches('\r'));
+                let glyphs = rustybuzz::shape(&face, &[], buffer);
+                let positions = glyphs.glyph_positions();
+
+                // the spacing goes only between the characters, so that it doesn't shift the alignment
+                let spacing = style.letter_spacing * positions.len().saturating_sub(1) as f32;
+                let advance = positions.iter().map(|p| p.x_advance).sum::<i32>();
+                let width = advance as f32 * scale + spacing;
+
+                let mut pen_x = match style.align {
+                    TextAlign::Left => 0.0,
+                    TextAlign::Center => -width / 2.0,
+                    TextAlign::Right => -width,
+                };
+                let baseline = line_index as f32 * style.line_height * style.size;
+                for (info, position) in glyphs.glyph_infos().iter().zip(positions) {
+                    outline.x = pen_x + position.x_offset as f32 * scale;
+                    outline.y = baseline - position.y_offset as f32 * scale;
+                    face.outline_glyph(ttf_parser::GlyphId(info.glyph_id as u16), &mut outline);
+
+                    pen_x += position.x_advance as f32 * scale + style.letter_spacing;
+                }
+            }
+
+            outline.builder.finish()
+        })
+        .flatten()?;
+
+    if emboldening > 0.0 {
+        embolden(path, emboldening)
+    } else {
+        Some(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{outline, FontStyle, TextAlign, TextStyle};
+
+    fn fontdb() -> fontdb::Database {
+        let mut fontdb = fontdb::Database::new();
+        fontdb.load_font_data(include_bytes!("../../fonts/Comic Sans MS.ttf").into());
+        fontdb
+    }
+
+    fn style() -> TextStyle {
+        TextStyle {
+            family: "Comic Sans MS".to_string(),
+            weight: 400,
+            style: FontStyle::Normal,
+            size: 100.0,
+            letter_spacing: 0.0,
+            line_height: 1.2,
+            align: TextAlign::Left,
+        }
+    }
+
+    #[test]
+    fn outline_bounds() {
+        let fontdb = fontdb();
+        let path = outline(&fontdb, "<b>&amp;</b>", &style()).unwrap();
+
+        let bounds = path.bounds();
+        // the text starts at the origin, and mostly sits above the baseline
+        assert!(bounds.left() >= 0.0 && bounds.left() < 20.0);
+        assert!(bounds.top() < -50.0 && bounds.bottom() > 0.0);
+        // the markup characters are drawn as they are
+        assert!(bounds.width() > 500.0);
+
+        // an unknown family falls back to the loaded font
+        let fallback = TextStyle {
+            family: "Times New Roman".to_string(),
+            ..style()
+        };
+        let fallback = outline(&fontdb, "<b>&amp;</b>", &fallback).unwrap();
+        assert_eq!(fallback.bounds(), bounds);
+
+        assert!(outline(&fontdb, "   ", &style()).is_none());
+    }
+
+    #[test]
+    fn synthetic_bold_and_italic() {
+        let fontdb = fontdb();
+        let coverage = |style: TextStyle| {
+            let path = outline(&fontdb, "Bo", &style).unwrap();
+            let mut pixmap = tiny_skia::Pixmap::new(300, 150).unwrap();
+            pixmap.fill_path(
+                &path,
+                &tiny_skia::Paint::default(),
+                tiny_skia::FillRule::Winding,
+                tiny_skia::Transform::from_translate(50.0, 110.0),
+                None,
+            );
+            (
+                path.bounds(),
+                pixmap
+                    .pixels()
+                    .iter()
+                    .map(|p| p.alpha())
+                    .collect::<Vec<_>>(),
+            )
+        };
+
+        // there's only the regular face, so the bold is made by thickening the glyphs
+        let (regular_bounds, regular) = coverage(style());
+        let (bold_bounds, bold) = coverage(TextStyle {
+            weight: 700,
+            ..style()
+        });
+        assert!(bold_bounds.width() > regular_bounds.width() + 3.0);
+        // the thickening covers the glyphs instead of hollowing them out, and doesn't fill the holes
+        assert!(regular.iter().zip(&bold).all(|(r, b)| b >= r));
+        assert!(
+            bold.iter().filter(|&&a| a == 255).count()
+                > regular.iter().filter(|&&a| a == 255).count()
+        );
+        assert!(bold.iter().filter(|&&a| a == 0).count() > 300 * 150 / 2);
+
+        // the italic is slanted to the right, around the baseline
+        let (italic_bounds, italic) = coverage(TextStyle {
+            style: FontStyle::Italic,
+            ..style()
+        });
+        assert!(italic_bounds.right() > regular_bounds.right() + 3.0);
+        assert!((italic_bounds.bottom() - regular_bounds.bottom()).abs() < 0.01);
+        assert_ne!(italic, regular);
+    }
+
+    #[test]
+    fn outline_layout() {
+        let fontdb = fontdb();
+        let bounds = |text, style| outline(&fontdb, text, &style).unwrap().bounds();
+
+        let line = bounds("ABC", style());
+        let spaced = bounds(
+            "ABC",
+            TextStyle {
+                letter_spacing: 50.0,
+                ..style()
+            },
+        );
+        assert!((spaced.width() - line.width() - 100.0).abs() < 0.01);
+
+        // the second line is one line height lower
+        let two_lines = bounds("ABC\nABC", style());
+        assert!((two_lines.bottom() - line.bottom() - 120.0).abs() < 0.01);
+        let tall = bounds(
+            "ABC\r\nABC",
+            TextStyle {
+                line_height: 2.0,
+                ..style()
+            },
+        );
+        assert!((tall.bottom() - line.bottom() - 200.0).abs() < 0.01);
+
+        let centered = bounds(
+            "ABC",
+            TextStyle {
+                align: TextAlign::Center,
+                ..style()
+            },
+        );
+        assert!((centered.left() + centered.right()).abs() < 20.0);
+        let right = bounds(
+            "ABC",
+            TextStyle {
+                align: TextAlign::Right,
+                ..style()
+            },
+        );
+        assert!(right.right() <= 0.0 && right.right() > -20.0);
+    }
+}
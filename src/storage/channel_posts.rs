@@ -0,0 +1,60 @@
+use super::{now, Storage};
+use anyhow::{Context as _, Result};
+use rusqlite::params;
+
+/// Channel posts that were already watermarked
+pub struct ChannelPosts<'a> {
+    storage: &'a Storage,
+    account: &'a str,
+}
+
+impl<'a> ChannelPosts<'a> {
+    pub(super) fn new(storage: &'a Storage, account: &'a str) -> Self {
+        Self { storage, account }
+    }
+
+    /// Marks the post as processed, returning `false` if it already was
+    pub fn mark_processed(&self, channel_id: i64, message_id: i32) -> Result<bool> {
+        let inserted = self
+            .storage
+            .connection()
+            .execute(
+                "INSERT OR IGNORE INTO channel_posts (account, channel_id, message_id, processed_at)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![self.account, channel_id, message_id, now()],
+            )
+            .context("Marking a channel post as processed")?;
+
+        Ok(inserted == 1)
+    }
+
+    pub fn unmark_processed(&self, channel_id: i64, message_id: i32) -> Result<()> {
+        self.storage
+            .connection()
+            .execute(
+                "DELETE FROM channel_posts WHERE account = ?1 AND channel_id = ?2 AND message_id = ?3",
+                params![self.account, channel_id, message_id],
+            )
+            .context("Unmarking a channel post as processed")?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::Storage;
+
+    #[test]
+    fn processed_only_once() {
+        let storage = Storage::open_in_memory().unwrap();
+        let posts = storage.channel_posts("test");
+
+        assert!(posts.mark_processed(-100, 1).unwrap());
+        assert!(!posts.mark_processed(-100, 1).unwrap());
+        assert!(posts.mark_processed(-100, 2).unwrap());
+
+        posts.unmark_processed(-100, 1).unwrap();
+        assert!(posts.mark_processed(-100, 1).unwrap());
+    }
+}
@@ -0,0 +1,79 @@
+use super::{presets_from_sql, presets_to_sql, Storage};
+use anyhow::{Context as _, Result};
+use rusqlite::{params, OptionalExtension as _};
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ChatSettings {
+    /// Watermark every photo sent to the chat, not only the ones the bot is asked about
+    pub auto_watermark: bool,
+    /// Names of the presets to use in this chat. All the account presets are used if not set
+    pub presets: Option<Vec<String>>,
+}
+
+/// Settings of the group chats, as changed by the chat admins
+pub struct Chats<'a> {
+    storage: &'a Storage,
+    account: &'a str,
+}
+
+impl<'a> Chats<'a> {
+    pub(super) fn new(storage: &'a Storage, account: &'a str) -> Self {
+        Self { storage, account }
+    }
+
+    pub fn get(&self, chat_id: i64) -> Result<Option<ChatSettings>> {
+        self.storage
+            .connection()
+            .query_row(
+                "SELECT auto_watermark, presets FROM chats WHERE account = ?1 AND id = ?2",
+                params![self.account, chat_id],
+                |row| {
+                    Ok(ChatSettings {
+                        auto_watermark: row.get(0)?,
+                        presets: presets_from_sql(row.get(1)?)?,
+                    })
+                },
+            )
+            .optional()
+            .context("Getting chat settings")
+    }
+
+    pub fn set(&self, chat_id: i64, settings: &ChatSettings) -> Result<()> {
+        self.storage
+            .connection()
+            .execute(
+                "INSERT OR REPLACE INTO chats (account, id, auto_watermark, presets)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    self.account,
+                    chat_id,
+                    settings.auto_watermark,
+                    presets_to_sql(&settings.presets)
+                ],
+            )
+            .context("Saving chat settings")?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ChatSettings;
+    use crate::storage::Storage;
+
+    #[test]
+    fn roundtrip() {
+        let storage = Storage::open_in_memory().unwrap();
+        let chats = storage.chats("test");
+
+        assert_eq!(chats.get(-100).unwrap(), None);
+
+        let settings = ChatSettings {
+            auto_watermark: true,
+            presets: Some(vec!["a".to_string(), "b".to_string()]),
+        };
+        chats.set(-100, &settings).unwrap();
+        assert_eq!(chats.get(-100).unwrap(), Some(settings));
+    }
+}
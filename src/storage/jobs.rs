@@ -0,0 +1,262 @@
+use super::{now, Storage};
+use anyhow::{Context as _, Result};
+use rusqlite::params;
+use std::time::Duration;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum JobOutcome {
+    Success,
+    Cancelled,
+    Error,
+}
+
+impl JobOutcome {
+    fn as_str(self) -> &'static str {
+        match self {
+            JobOutcome::Success => "success",
+            JobOutcome::Cancelled => "cancelled",
+            JobOutcome::Error => "error",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct JobRecord {
+    pub user_id: i64,
+    pub chat_id: i64,
+    pub presets: Vec<String>,
+    pub photo_count: usize,
+    /// Total size of the downloaded photos
+    pub image_bytes: usize,
+    pub render_duration: Duration,
+    pub upload_duration: Duration,
+    /// Time from the start of the job to its end, as seen by the user
+    pub duration: Duration,
+    pub outcome: JobOutcome,
+}
+
+/// Aggregated numbers over the jobs in some period
+#[derive(Debug, Default, PartialEq)]
+pub struct JobTotals {
+    pub jobs: u64,
+    pub photos: u64,
+    pub failed: u64,
+    pub cancelled: u64,
+    /// Averages are only over the successful jobs, `None` if there were none
+    pub average_duration: Option<Duration>,
+    pub average_render_duration: Option<Duration>,
+    pub average_upload_duration: Option<Duration>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct UserUsage {
+    pub user_id: i64,
+    /// Known only for the users that have talked to the bot in private
+    pub username: Option<String>,
+    pub jobs: u64,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct PresetUsage {
+    pub name: String,
+    pub jobs: u64,
+}
+
+/// History of the watermarking jobs
+pub struct Jobs<'a> {
+    storage: &'a Storage,
+    account: &'a str,
+}
+
+impl<'a> Jobs<'a> {
+    pub(super) fn new(storage: &'a Storage, account: &'a str) -> Self {
+        Self { storage, account }
+    }
+
+    pub fn record(&self, job: &JobRecord) -> Result<()> {
+        self.storage
+            .connection()
+            .execute(
+                "INSERT INTO jobs (
+                    account, user_id, chat_id, presets, photo_count, outcome, finished_at,
+                    image_bytes, render_ms, upload_ms, duration_ms
+                 )
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+                params![
+                    self.account,
+                    job.user_id,
+                    job.chat_id,
+                    serde_json::to_string(&job.presets).expect("Serializing a list of strings"),
+                    job.photo_count,
+                    job.outcome.as_str(),
+                    now(),
+                    job.image_bytes,
+                    job.render_duration.as_millis() as i64,
+                    job.upload_duration.as_millis() as i64,
+                    job.duration.as_millis() as i64,
+                ],
+            )
+            .context("Recording a job")?;
+
+        Ok(())
+    }
+
+    /// Totals over the jobs finished since the unix timestamp
+    pub fn totals(&self, since: i64) -> Result<JobTotals> {
+        let millis = |ms: Option<f64>| ms.map(|ms| Duration::from_secs_f64(ms / 1000.0));
+
+        self.storage
+            .connection()
+            .query_row(
+                "SELECT
+                    COUNT(*),
+                    COALESCE(SUM(photo_count), 0),
+                    COUNT(*) FILTER (WHERE outcome = 'error'),
+                    COUNT(*) FILTER (WHERE outcome = 'cancelled'),
+                    AVG(duration_ms) FILTER (WHERE outcome = 'success'),
+                    AVG(render_ms) FILTER (WHERE outcome = 'success'),
+                    AVG(upload_ms) FILTER (WHERE outcome = 'success')
+                 FROM jobs WHERE account = ?1 AND finished_at >= ?2",
+                params![self.account, since],
+                |row| {
+                    Ok(JobTotals {
+                        jobs: row.get(0)?,
+                        photos: row.get(1)?,
+                        failed: row.get(2)?,
+                        cancelled: row.get(3)?,
+                        average_duration: millis(row.get(4)?),
+                        average_render_duration: millis(row.get(5)?),
+                        average_upload_duration: millis(row.get(6)?),
+                    })
+                },
+            )
+            .context("Getting job totals")
+    }
+
+    /// Users with the most jobs finished since the unix timestamp
+    pub fn top_users(&self, since: i64, limit: usize) -> Result<Vec<UserUsage>> {
+        let connection = self.storage.connection();
+        let mut statement = connection.prepare(
+            "SELECT jobs.user_id, users.username, COUNT(*) AS count
+             FROM jobs
+             LEFT JOIN users ON users.account = jobs.account AND users.id = jobs.user_id
+             WHERE jobs.account = ?1 AND jobs.finished_at >= ?2
+             GROUP BY jobs.user_id
+             ORDER BY count DESC, jobs.user_id
+             LIMIT ?3",
+        )?;
+        let users = statement
+            .query_map(params![self.account, since, limit], |row| {
+                Ok(UserUsage {
+                    user_id: row.get(0)?,
+                    username: row.get(1)?,
+                    jobs: row.get(2)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()
+            .context("Getting top users")?;
+
+        Ok(users)
+    }
+
+    /// Presets used in the most jobs finished since the unix timestamp
+    pub fn top_presets(&self, since: i64, limit: usize) -> Result<Vec<PresetUsage>> {
+        let connection = self.storage.connection();
+        let mut statement = connection.prepare(
+            "SELECT preset.value, COUNT(*) AS count
+             FROM jobs, json_each(jobs.presets) AS preset
+             WHERE jobs.account = ?1 AND jobs.finished_at >= ?2
+             GROUP BY preset.value
+             ORDER BY count DESC, preset.value
+             LIMIT ?3",
+        )?;
+        let presets = statement
+            .query_map(params![self.account, since, limit], |row| {
+                Ok(PresetUsage {
+                    name: row.get(0)?,
+                    jobs: row.get(1)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()
+            .context("Getting top presets")?;
+
+        Ok(presets)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{JobOutcome, JobRecord, JobTotals, PresetUsage, UserUsage};
+    use crate::storage::Storage;
+    use std::time::Duration;
+
+    fn job(user_id: i64, presets: &[&str], outcome: JobOutcome) -> JobRecord {
+        JobRecord {
+            user_id,
+            chat_id: user_id,
+            presets: presets.iter().map(|p| p.to_string()).collect(),
+            photo_count: 2,
+            image_bytes: 1000,
+            render_duration: Duration::from_secs(1),
+            upload_duration: Duration::from_secs(2),
+            duration: Duration::from_secs(4),
+            outcome,
+        }
+    }
+
+    #[test]
+    fn stats() {
+        let storage = Storage::open_in_memory().unwrap();
+        storage.users("test").touch(1, Some("one"), &[1]).unwrap();
+
+        let jobs = storage.jobs("test");
+        jobs.record(&job(1, &["a", "b"], JobOutcome::Success))
+            .unwrap();
+        jobs.record(&job(1, &["a"], JobOutcome::Error)).unwrap();
+        jobs.record(&job(2, &["b"], JobOutcome::Cancelled)).unwrap();
+        jobs.record(&job(2, &["b"], JobOutcome::Success)).unwrap();
+        jobs.record(&job(1, &["a"], JobOutcome::Success)).unwrap();
+        storage
+            .jobs("other")
+            .record(&job(3, &["c"], JobOutcome::Success))
+            .unwrap();
+
+        assert_eq!(
+            jobs.totals(0).unwrap(),
+            JobTotals {
+                jobs: 5,
+                photos: 10,
+                failed: 1,
+                cancelled: 1,
+                average_duration: Some(Duration::from_secs(4)),
+                average_render_duration: Some(Duration::from_secs(1)),
+                average_upload_duration: Some(Duration::from_secs(2)),
+            }
+        );
+        assert_eq!(
+            jobs.top_users(0, 10).unwrap(),
+            [
+                UserUsage {
+                    user_id: 1,
+                    username: Some("one".to_string()),
+                    jobs: 3
+                },
+                UserUsage {
+                    user_id: 2,
+                    username: None,
+                    jobs: 2
+                },
+            ]
+        );
+        assert_eq!(
+            jobs.top_presets(0, 1).unwrap(),
+            [PresetUsage {
+                name: "a".to_string(),
+                jobs: 3
+            }]
+        );
+
+        // nothing in the future
+        assert_eq!(jobs.totals(i64::MAX).unwrap(), JobTotals::default());
+    }
+}
@@ -0,0 +1,246 @@
+//! Persistent state of the bot, stored in an SQLite database
+//!
+//! The queries are small and fast, so they are run directly on the async threads.
+
+mod channel_posts;
+mod chats;
+mod jobs;
+mod presets;
+mod user_presets;
+mod users;
+
+pub use channel_posts::ChannelPosts;
+pub use chats::{ChatSettings, Chats};
+pub use jobs::{JobOutcome, JobRecord, JobTotals, Jobs};
+pub use presets::{Presets, StoredPreset};
+pub use user_presets::{UserPreset, UserPresets};
+pub use users::Users;
+
+use anyhow::{Context as _, Result};
+use rusqlite::Connection;
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::info;
+
+/// Migrations of the database schema, applied in order
+///
+/// The number of the applied migrations is stored in the `user_version` pragma.
+/// Never change the already released migrations, add new ones instead.
+const MIGRATIONS: &[&str] = &[
+    // 1: initial schema
+    r#"
+    CREATE TABLE users (
+        account TEXT NOT NULL,
+        id INTEGER NOT NULL,
+        username TEXT,
+        first_seen INTEGER NOT NULL,
+        last_seen INTEGER NOT NULL,
+        blocked INTEGER NOT NULL DEFAULT 0,
+        PRIMARY KEY (account, id)
+    );
+
+    CREATE TABLE chats (
+        account TEXT NOT NULL,
+        id INTEGER NOT NULL,
+        auto_watermark INTEGER NOT NULL,
+        presets TEXT,
+        PRIMARY KEY (account, id)
+    );
+
+    CREATE TABLE jobs (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        account TEXT NOT NULL,
+        user_id INTEGER NOT NULL,
+        chat_id INTEGER NOT NULL,
+        presets TEXT NOT NULL,
+        photo_count INTEGER NOT NULL,
+        outcome TEXT NOT NULL,
+        finished_at INTEGER NOT NULL
+    );
+
+    CREATE TABLE channel_posts (
+        account TEXT NOT NULL,
+        channel_id INTEGER NOT NULL,
+        message_id INTEGER NOT NULL,
+        processed_at INTEGER NOT NULL,
+        PRIMARY KEY (account, channel_id, message_id)
+    );
+    "#,
+    // 2: job statistics
+    r#"
+    ALTER TABLE jobs ADD COLUMN image_bytes INTEGER;
+    ALTER TABLE jobs ADD COLUMN render_ms INTEGER;
+    ALTER TABLE jobs ADD COLUMN upload_ms INTEGER;
+    ALTER TABLE jobs ADD COLUMN duration_ms INTEGER;
+
+    CREATE INDEX jobs_finished_at ON jobs (account, finished_at);
+    "#,
+    // 3: remember how to reach the users, for broadcasts
+    r#"
+    ALTER TABLE users ADD COLUMN packed_chat BLOB;
+    "#,
+    // 4: presets managed from the chat
+    r#"
+    CREATE TABLE presets (
+        account TEXT NOT NULL,
+        name TEXT NOT NULL,
+        definition TEXT NOT NULL,
+        created_by INTEGER NOT NULL,
+        created_at INTEGER NOT NULL,
+        updated_at INTEGER NOT NULL,
+        PRIMARY KEY (account, name)
+    );
+    "#,
+    // 5: personal presets of the users
+    r#"
+    CREATE TABLE user_presets (
+        account TEXT NOT NULL,
+        user_id INTEGER NOT NULL,
+        name TEXT NOT NULL,
+        template TEXT NOT NULL,
+        text TEXT NOT NULL,
+        created_at INTEGER NOT NULL,
+        PRIMARY KEY (account, user_id, name)
+    );
+    "#,
+    // 6: let the users choose which personal presets are applied to their photos
+    r#"
+    ALTER TABLE user_presets ADD COLUMN active INTEGER NOT NULL DEFAULT 1;
+    "#,
+];
+
+pub struct Storage {
+    connection: Mutex<Connection>,
+}
+
+impl Storage {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        info!("Opening the database at {}", path.display());
+        let connection = Connection::open(path)
+            .with_context(|| format!("Opening the database at {}", path.display()))?;
+
+        Self::from_connection(connection)
+    }
+
+    #[cfg(test)]
+    pub fn open_in_memory() -> Result<Self> {
+        Self::from_connection(Connection::open_in_memory()?)
+    }
+
+    fn from_connection(mut connection: Connection) -> Result<Self> {
+        // the journal mode pragma returns the resulting mode, which is `memory` for in-memory databases
+        connection
+            .pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))
+            .context("Enabling WAL")?;
+        migrate(&mut connection)?;
+
+        Ok(Self {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    fn connection(&self) -> MutexGuard<'_, Connection> {
+        self.connection.lock().unwrap()
+    }
+
+    pub fn users<'a>(&'a self, account: &'a str) -> Users<'a> {
+        Users::new(self, account)
+    }
+
+    pub fn chats<'a>(&'a self, account: &'a str) -> Chats<'a> {
+        Chats::new(self, account)
+    }
+
+    pub fn jobs<'a>(&'a self, account: &'a str) -> Jobs<'a> {
+        Jobs::new(self, account)
+    }
+
+    pub fn presets<'a>(&'a self, account: &'a str) -> Presets<'a> {
+        Presets::new(self, account)
+    }
+
+    pub fn user_presets<'a>(&'a self, account: &'a str) -> UserPresets<'a> {
+        UserPresets::new(self, account)
+    }
+
+    pub fn channel_posts<'a>(&'a self, account: &'a str) -> ChannelPosts<'a> {
+        ChannelPosts::new(self, account)
+    }
+}
+
+fn migrate(connection: &mut Connection) -> Result<()> {
+    let version: usize = connection
+        .pragma_query_value(None, "user_version", |row| row.get(0))
+        .context("Getting the schema version")?;
+
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        info!("Applying database migration {}", i + 1);
+
+        let transaction = connection.transaction()?;
+        transaction
+            .execute_batch(migration)
+            .with_context(|| format!("Applying migration {}", i + 1))?;
+        transaction.pragma_update(None, "user_version", i + 1)?;
+        transaction.commit()?;
+    }
+
+    Ok(())
+}
+
+/// Current time as a unix timestamp, the way it's stored in the database
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System time is before the unix epoch")
+        .as_secs() as i64
+}
+
+/// Lists of preset names are stored as JSON arrays
+fn presets_to_sql(presets: &Option<Vec<String>>) -> Option<String> {
+    presets
+        .as_ref()
+        .map(|presets| serde_json::to_string(presets).expect("Serializing a list of strings"))
+}
+
+fn presets_from_sql(presets: Option<String>) -> rusqlite::Result<Option<Vec<String>>> {
+    presets
+        .map(|presets| {
+            serde_json::from_str(&presets).map_err(|e| {
+                rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, e.into())
+            })
+        })
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Storage, MIGRATIONS};
+
+    #[test]
+    fn migrations_apply() {
+        let storage = Storage::open_in_memory().unwrap();
+        let version: usize = storage
+            .connection()
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .unwrap();
+
+        assert_eq!(version, MIGRATIONS.len());
+    }
+
+    #[test]
+    fn migrations_are_idempotent() {
+        let path = std::env::temp_dir().join(format!(
+            "serezha-watermark-bot-test-{}.sqlite",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+
+        drop(Storage::open(&path).unwrap());
+        // opening an already migrated database shouldn't try to migrate it again
+        drop(Storage::open(&path).unwrap());
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,114 @@
+use super::{now, Storage};
+use anyhow::{Context as _, Result};
+use rusqlite::params;
+
+/// A preset added from the chat, stored as the YAML text it was defined with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredPreset {
+    pub name: String,
+    pub definition: String,
+}
+
+/// Presets managed by the superusers from the chat
+pub struct Presets<'a> {
+    storage: &'a Storage,
+    account: &'a str,
+}
+
+impl<'a> Presets<'a> {
+    pub(super) fn new(storage: &'a Storage, account: &'a str) -> Self {
+        Self { storage, account }
+    }
+
+    pub fn list(&self) -> Result<Vec<StoredPreset>> {
+        let connection = self.storage.connection();
+        let mut statement = connection.prepare(
+            "SELECT name, definition FROM presets WHERE account = ?1 ORDER BY created_at, name",
+        )?;
+        let presets = statement
+            .query_map(params![self.account], |row| {
+                Ok(StoredPreset {
+                    name: row.get(0)?,
+                    definition: row.get(1)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()
+            .context("Listing presets")?;
+
+        Ok(presets)
+    }
+
+    /// Adds a new preset, returning `false` if a preset with this name already exists
+    pub fn insert(&self, preset: &StoredPreset, created_by: i64) -> Result<bool> {
+        let inserted = self
+            .storage
+            .connection()
+            .execute(
+                "INSERT OR IGNORE INTO presets (account, name, definition, created_by, created_at, updated_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?5)",
+                params![self.account, preset.name, preset.definition, created_by, now()],
+            )
+            .context("Adding a preset")?;
+
+        Ok(inserted == 1)
+    }
+
+    /// Changes the definition of a preset, returning `false` if there's no such preset
+    pub fn update(&self, preset: &StoredPreset) -> Result<bool> {
+        let updated = self
+            .storage
+            .connection()
+            .execute(
+                "UPDATE presets SET definition = ?3, updated_at = ?4 WHERE account = ?1 AND name = ?2",
+                params![self.account, preset.name, preset.definition, now()],
+            )
+            .context("Updating a preset")?;
+
+        Ok(updated == 1)
+    }
+
+    /// Deletes a preset, returning `false` if there's no such preset
+    pub fn delete(&self, name: &str) -> Result<bool> {
+        let deleted = self
+            .storage
+            .connection()
+            .execute(
+                "DELETE FROM presets WHERE account = ?1 AND name = ?2",
+                params![self.account, name],
+            )
+            .context("Deleting a preset")?;
+
+        Ok(deleted == 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::StoredPreset;
+    use crate::storage::Storage;
+
+    #[test]
+    fn crud() {
+        let storage = Storage::open_in_memory().unwrap();
+        let presets = storage.presets("test");
+
+        let preset = StoredPreset {
+            name: "test".to_string(),
+            definition: "alpha: 32".to_string(),
+        };
+        assert!(presets.insert(&preset, 1).unwrap());
+        assert!(!presets.insert(&preset, 1).unwrap());
+        assert_eq!(presets.list().unwrap(), std::slice::from_ref(&preset));
+
+        let updated = StoredPreset {
+            definition: "alpha: 64".to_string(),
+            ..preset
+        };
+        assert!(presets.update(&updated).unwrap());
+        assert_eq!(presets.list().unwrap(), [updated]);
+
+        assert!(presets.delete("test").unwrap());
+        assert!(!presets.delete("test").unwrap());
+        assert_eq!(presets.list().unwrap(), []);
+    }
+}
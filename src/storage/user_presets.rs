@@ -0,0 +1,133 @@
+use super::{now, Storage};
+use anyhow::{Context as _, Result};
+use rusqlite::params;
+
+/// A personal preset: a text put into the style of one of the global presets
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserPreset {
+    pub name: String,
+    /// Name of the global preset to take the style from
+    pub template: String,
+    pub text: String,
+    /// Whether the preset is applied to the photos the user sends in private
+    pub active: bool,
+}
+
+/// Personal presets of the users
+pub struct UserPresets<'a> {
+    storage: &'a Storage,
+    account: &'a str,
+}
+
+impl<'a> UserPresets<'a> {
+    pub(super) fn new(storage: &'a Storage, account: &'a str) -> Self {
+        Self { storage, account }
+    }
+
+    pub fn list(&self, user_id: i64) -> Result<Vec<UserPreset>> {
+        let connection = self.storage.connection();
+        let mut statement = connection.prepare(
+            "SELECT name, template, text, active FROM user_presets
+             WHERE account = ?1 AND user_id = ?2
+             ORDER BY created_at, name",
+        )?;
+        let presets = statement
+            .query_map(params![self.account, user_id], |row| {
+                Ok(UserPreset {
+                    name: row.get(0)?,
+                    template: row.get(1)?,
+                    text: row.get(2)?,
+                    active: row.get(3)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()
+            .context("Listing user presets")?;
+
+        Ok(presets)
+    }
+
+    /// Adds a preset, returning `false` if the user already has a preset with this name
+    pub fn insert(&self, user_id: i64, preset: &UserPreset) -> Result<bool> {
+        let inserted = self
+            .storage
+            .connection()
+            .execute(
+                "INSERT OR IGNORE INTO user_presets (account, user_id, name, template, text, active, created_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    self.account,
+                    user_id,
+                    preset.name,
+                    preset.template,
+                    preset.text,
+                    preset.active,
+                    now()
+                ],
+            )
+            .context("Adding a user preset")?;
+
+        Ok(inserted == 1)
+    }
+
+    /// Turns the preset on or off, returning `false` if the user has no such preset
+    pub fn set_active(&self, user_id: i64, name: &str, active: bool) -> Result<bool> {
+        let updated = self
+            .storage
+            .connection()
+            .execute(
+                "UPDATE user_presets SET active = ?4 WHERE account = ?1 AND user_id = ?2 AND name = ?3",
+                params![self.account, user_id, name, active],
+            )
+            .context("Updating a user preset")?;
+
+        Ok(updated == 1)
+    }
+
+    /// Deletes a preset, returning `false` if the user has no such preset
+    pub fn delete(&self, user_id: i64, name: &str) -> Result<bool> {
+        let deleted = self
+            .storage
+            .connection()
+            .execute(
+                "DELETE FROM user_presets WHERE account = ?1 AND user_id = ?2 AND name = ?3",
+                params![self.account, user_id, name],
+            )
+            .context("Deleting a user preset")?;
+
+        Ok(deleted == 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::UserPreset;
+    use crate::storage::Storage;
+
+    #[test]
+    fn presets_are_per_user() {
+        let storage = Storage::open_in_memory().unwrap();
+        let presets = storage.user_presets("test");
+
+        let preset = UserPreset {
+            name: "mine".to_string(),
+            template: "global".to_string(),
+            text: "hello".to_string(),
+            active: true,
+        };
+        assert!(presets.insert(1, &preset).unwrap());
+        assert!(!presets.insert(1, &preset).unwrap());
+        assert!(presets.insert(2, &preset).unwrap());
+
+        assert!(presets.delete(2, "mine").unwrap());
+        assert_eq!(presets.list(1).unwrap(), std::slice::from_ref(&preset));
+        assert_eq!(presets.list(2).unwrap(), []);
+
+        assert!(presets.set_active(1, "mine", false).unwrap());
+        assert!(!presets.set_active(2, "mine", false).unwrap());
+        let inactive = UserPreset {
+            active: false,
+            ..preset
+        };
+        assert_eq!(presets.list(1).unwrap(), [inactive]);
+    }
+}
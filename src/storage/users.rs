@@ -0,0 +1,130 @@
+use super::{now, Storage};
+use anyhow::{Context as _, Result};
+use rusqlite::params;
+
+/// A user that can be messaged by the bot
+#[derive(Debug, PartialEq, Eq)]
+pub struct Recipient {
+    pub id: i64,
+    /// Serialized `PackedChat`, which has the access hash needed to message the user
+    pub packed_chat: Vec<u8>,
+}
+
+/// Users that have talked to the account
+pub struct Users<'a> {
+    storage: &'a Storage,
+    account: &'a str,
+}
+
+impl<'a> Users<'a> {
+    pub(super) fn new(storage: &'a Storage, account: &'a str) -> Self {
+        Self { storage, account }
+    }
+
+    /// Records that the user has talked to the bot, unblocking them if they were blocked
+    pub fn touch(&self, id: i64, username: Option<&str>, packed_chat: &[u8]) -> Result<()> {
+        let now = now();
+        self.storage
+            .connection()
+            .execute(
+                "INSERT INTO users (account, id, username, first_seen, last_seen, packed_chat)
+                 VALUES (?1, ?2, ?3, ?4, ?4, ?5)
+                 ON CONFLICT (account, id) DO UPDATE
+                 SET username = excluded.username, last_seen = excluded.last_seen,
+                     packed_chat = excluded.packed_chat, blocked = 0",
+                params![self.account, id, username, now, packed_chat],
+            )
+            .context("Recording a user")?;
+
+        Ok(())
+    }
+
+    /// Marks the user as having blocked the bot, so that they are not messaged anymore
+    pub fn set_blocked(&self, id: i64) -> Result<()> {
+        self.storage
+            .connection()
+            .execute(
+                "UPDATE users SET blocked = 1 WHERE account = ?1 AND id = ?2",
+                params![self.account, id],
+            )
+            .context("Marking a user as blocked")?;
+
+        Ok(())
+    }
+
+    /// Users that can be messaged, i.e. not blocked and with a known access hash
+    pub fn recipients(&self) -> Result<Vec<Recipient>> {
+        let connection = self.storage.connection();
+        let mut statement = connection.prepare(
+            "SELECT id, packed_chat FROM users
+             WHERE account = ?1 AND blocked = 0 AND packed_chat IS NOT NULL
+             ORDER BY id",
+        )?;
+        let recipients = statement
+            .query_map(params![self.account], |row| {
+                Ok(Recipient {
+                    id: row.get(0)?,
+                    packed_chat: row.get(1)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()
+            .context("Getting broadcast recipients")?;
+
+        Ok(recipients)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Recipient;
+    use crate::storage::Storage;
+
+    #[test]
+    fn touch_updates_user() {
+        let storage = Storage::open_in_memory().unwrap();
+        let users = storage.users("test");
+
+        users.touch(1, Some("first"), &[1]).unwrap();
+        users.touch(1, Some("second"), &[1]).unwrap();
+        storage.users("other").touch(1, None, &[1]).unwrap();
+
+        let usernames = storage
+            .connection()
+            .prepare("SELECT account, username FROM users WHERE id = 1 ORDER BY account")
+            .unwrap()
+            .query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, Option<String>>(1)?))
+            })
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            usernames,
+            [
+                ("other".to_string(), None),
+                ("test".to_string(), Some("second".to_string()))
+            ]
+        );
+    }
+
+    #[test]
+    fn blocked_users_are_not_recipients() {
+        let storage = Storage::open_in_memory().unwrap();
+        let users = storage.users("test");
+
+        users.touch(1, None, &[1]).unwrap();
+        users.touch(2, None, &[2]).unwrap();
+        users.set_blocked(1).unwrap();
+        assert_eq!(
+            users.recipients().unwrap(),
+            [Recipient {
+                id: 2,
+                packed_chat: vec![2]
+            }]
+        );
+
+        // talking to the bot again unblocks the user
+        users.touch(1, None, &[1]).unwrap();
+        assert_eq!(users.recipients().unwrap().len(), 2);
+    }
+}